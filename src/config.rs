@@ -0,0 +1,139 @@
+//! The config file, for per-game defaults.
+//!
+//! It lives at `$XDG_CONFIG_HOME/on-the-gpu/config.toml` (or `~/.config/on-the-gpu/config.toml`),
+//! and looks like:
+//!
+//! ```toml
+//! [games."space-game"]
+//! gpu = ["gamemode", "pvkrun"]
+//! env = { PROTON_NO_ESYNC = "1" }
+//!
+//! [modes.gamescope]
+//! args = ["-W", "2560", "-H", "1440"]
+//! ```
+//!
+//! The `games` sections are keyed by the game name given on the command line. The `modes` sections
+//! hold defaults for GPU modes, for every game; only `gamescope` has any so far. Flags given on the
+//! command line win over what's in here: e.g., `--gamescope-args` are passed after the `args` from
+//! `[modes.gamescope]`, and gamescope takes the last value of an option given twice.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::env_file;
+use crate::GpuMode;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Settings for each game, by game name.
+    #[serde(default)]
+    pub games: HashMap<String, GameConfig>,
+    /// Settings for GPU modes.
+    #[serde(default)]
+    pub modes: ModesConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GameConfig {
+    /// The GPU modes to use, if `--gpu` isn't given.
+    #[serde(default)]
+    pub gpu: Vec<GpuMode>,
+    /// Environment variables to set for the command.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModesConfig {
+    /// Settings for `--gpu gamescope`.
+    #[serde(default)]
+    pub gamescope: WrapperConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WrapperConfig {
+    /// Arguments for the wrapper, before any given on the command line.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Where the config file is, if we can tell.
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        // Per the spec, a relative path here is invalid, and should be ignored.
+        Some(dir) if PathBuf::from(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("on-the-gpu").join("config.toml"))
+}
+
+/// Load the config file. A missing file is the same as an empty one.
+pub fn load() -> anyhow::Result<Config> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    let config =
+        toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))?;
+    check(&config).with_context(|| format!("invalid config in {}", path.display()))?;
+    Ok(config)
+}
+
+/// Check what the TOML can't: that the `env` tables only set valid variable names.
+fn check(config: &Config) -> anyhow::Result<()> {
+    for (game_name, game) in &config.games {
+        if let Some(key) = game.env.keys().find(|key| !env_file::is_valid_key(key)) {
+            bail!(
+                "[games.{:?}] sets {:?}, which isn't a valid variable name (letters, digits and \
+                 underscores, not starting with a digit)",
+                game_name,
+                key
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_rejects_invalid_variable_names() {
+        let config = toml::from_str(
+            r#"
+            [games."space-game"]
+            env = { PROTON_NO_ESYNC = "1" }
+            "#,
+        )
+        .unwrap();
+        assert!(check(&config).is_ok());
+
+        let config = toml::from_str(
+            r#"
+            [games."space-game"]
+            env = { "A=B" = "c" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            check(&config).unwrap_err().to_string(),
+            "[games.\"space-game\"] sets \"A=B\", which isn't a valid variable name (letters, \
+             digits and underscores, not starting with a digit)",
+        );
+    }
+}
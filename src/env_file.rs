@@ -0,0 +1,215 @@
+//! Parsing `--env-file`s: dotenv-style files of environment variables.
+//!
+//! Each line is `KEY=VALUE` (optionally prefixed with `export `). Blank lines and lines starting
+//! with `#` are ignored. A value may be quoted: within `'…'` everything is literal, and within
+//! `"…"` a backslash escapes `"`, `\` and `n` (a newline), and is kept before anything else. An
+//! unquoted value ends at ` #`, which starts a comment; a quoted one may be followed by a comment,
+//! too. Values don't have to be UTF-8.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+/// Read the variables in the env file at `path`, in the order they appear.
+pub fn read(path: &Path) -> anyhow::Result<Vec<(String, OsString)>> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    contents
+        .split(|&byte| byte == b'\n')
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            parse_line(line)
+                .with_context(|| format!("{}, line {}", path.display(), idx + 1))
+                .transpose()
+        })
+        .collect()
+}
+
+/// Parse one line; blank lines and comments give `None`.
+fn parse_line(line: &[u8]) -> anyhow::Result<Option<(String, OsString)>> {
+    let line = line.trim_ascii();
+    if line.is_empty() || line.starts_with(b"#") {
+        return Ok(None);
+    }
+    let line = line.strip_prefix(b"export ").unwrap_or(line);
+    let Some(eq) = line.iter().position(|&byte| byte == b'=') else {
+        bail!("expected KEY=VALUE");
+    };
+    let key = std::str::from_utf8(line[..eq].trim_ascii())
+        .ok()
+        .filter(|key| is_valid_key(key))
+        .with_context(|| {
+            format!(
+                "{:?} isn't a valid variable name",
+                OsStr::from_bytes(&line[..eq])
+            )
+        })?;
+    let value = parse_value(&line[eq + 1..])?;
+    Ok(Some((key.to_owned(), OsString::from_vec(value))))
+}
+
+/// Whether `key` is a valid variable name, as a shell would have it: letters, digits and
+/// underscores, not starting with a digit.
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse the value, everything after the `=`; a comment in it starts at ` #`, even right after the
+/// `=`.
+fn parse_value(raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let value = raw.trim_ascii_start();
+    match value.first() {
+        Some(b'\'') => match value[1..].iter().position(|&byte| byte == b'\'') {
+            Some(end) => {
+                check_after_quote(&value[end + 2..], '\'')?;
+                Ok(value[1..end + 1].to_vec())
+            }
+            None => bail!("unterminated ' quote"),
+        },
+        Some(b'"') => {
+            let mut parsed = Vec::new();
+            let mut bytes = value[1..].iter();
+            while let Some(&byte) = bytes.next() {
+                match byte {
+                    b'"' => {
+                        check_after_quote(bytes.as_slice(), '"')?;
+                        return Ok(parsed);
+                    }
+                    b'\\' => match bytes.next() {
+                        Some(b'n') => parsed.push(b'\n'),
+                        Some(&escaped @ (b'"' | b'\\')) => parsed.push(escaped),
+                        // Not an escape, e.g., in a Windows path; the backslash stays.
+                        Some(&other) => parsed.extend([b'\\', other]),
+                        None => break,
+                    },
+                    byte => parsed.push(byte),
+                }
+            }
+            bail!("unterminated \" quote")
+        }
+        _ => {
+            let end = raw
+                .windows(2)
+                .position(|pair| pair == b" #")
+                .unwrap_or(raw.len());
+            Ok(raw[..end].trim_ascii().to_vec())
+        }
+    }
+}
+
+/// Check what follows a closing quote: nothing, or a ` #` comment.
+fn check_after_quote(rest: &[u8], quote: char) -> anyhow::Result<()> {
+    let comment = rest.trim_ascii_start();
+    if !rest.is_empty() && (comment.len() == rest.len() || !comment.starts_with(b"#")) {
+        bail!("unexpected text after the closing {} quote", quote);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &[u8]) -> Option<(String, OsString)> {
+        parse_line(line).unwrap()
+    }
+
+    fn var(key: &str, value: &[u8]) -> Option<(String, OsString)> {
+        Some((key.to_owned(), OsString::from_vec(value.to_vec())))
+    }
+
+    fn error(line: &[u8]) -> String {
+        parse_line(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn blank_lines_and_comments() {
+        assert_eq!(parse(b""), None);
+        assert_eq!(parse(b"   "), None);
+        assert_eq!(parse(b"# FOO=bar"), None);
+        assert_eq!(parse(b"  # FOO=bar"), None);
+    }
+
+    #[test]
+    fn unquoted() {
+        assert_eq!(parse(b"FOO=bar"), var("FOO", b"bar"));
+        assert_eq!(parse(b" FOO = bar "), var("FOO", b"bar"));
+        assert_eq!(parse(b"FOO="), var("FOO", b""));
+        assert_eq!(parse(b"FOO=dxgi=n,b"), var("FOO", b"dxgi=n,b"));
+        assert_eq!(parse(b"FOO=bar # comment"), var("FOO", b"bar"));
+        assert_eq!(parse(b"FOO= # comment"), var("FOO", b""));
+        // Only ` #` starts a comment.
+        assert_eq!(parse(b"FOO=bar#baz"), var("FOO", b"bar#baz"));
+    }
+
+    #[test]
+    fn export_prefix() {
+        assert_eq!(parse(b"export FOO=bar"), var("FOO", b"bar"));
+    }
+
+    #[test]
+    fn single_quoted() {
+        assert_eq!(
+            parse(b"FOO='a \\n \"b\" # c'"),
+            var("FOO", b"a \\n \"b\" # c")
+        );
+        assert_eq!(parse(b"FOO='bar' # comment"), var("FOO", b"bar"));
+        assert_eq!(error(b"FOO='bar"), "unterminated ' quote");
+        assert_eq!(
+            error(b"FOO='bar'baz"),
+            "unexpected text after the closing ' quote"
+        );
+    }
+
+    #[test]
+    fn double_quoted() {
+        assert_eq!(
+            parse(b"FOO=\"a\\nb \\\"c\\\" \\\\ # d\""),
+            var("FOO", b"a\nb \"c\" \\ # d"),
+        );
+        assert_eq!(parse(b"FOO=\"bar\" # comment"), var("FOO", b"bar"));
+        // Anything else after a backslash isn't an escape.
+        assert_eq!(parse(b"FOO=\"C:\\Games\\x\""), var("FOO", b"C:\\Games\\x"));
+        assert_eq!(error(b"FOO=\"bar"), "unterminated \" quote");
+        assert_eq!(error(b"FOO=\"bar\\\""), "unterminated \" quote");
+        assert_eq!(
+            error(b"FOO=\"bar\" baz"),
+            "unexpected text after the closing \" quote"
+        );
+        assert_eq!(
+            error(b"FOO=\"bar\"# comment"),
+            "unexpected text after the closing \" quote"
+        );
+    }
+
+    #[test]
+    fn non_utf8_values() {
+        assert_eq!(parse(b"FOO=caf\xe9"), var("FOO", b"caf\xe9"));
+        assert_eq!(parse(b"FOO=\"caf\xe9\""), var("FOO", b"caf\xe9"));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(error(b"FOO"), "expected KEY=VALUE");
+        assert_eq!(error(b"1FOO=bar"), "\"1FOO\" isn't a valid variable name");
+        assert_eq!(error(b"=bar"), "\"\" isn't a valid variable name");
+    }
+
+    #[test]
+    fn read_reports_the_line() {
+        let path = std::env::temp_dir().join(format!("on-the-gpu-test-{}.env", std::process::id()));
+        std::fs::write(&path, b"# Settings\nFOO=bar\n\nBAR\n").unwrap();
+        let error = read(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            format!("{:#}", error),
+            format!("{}, line 4: expected KEY=VALUE", path.display()),
+        );
+    }
+}
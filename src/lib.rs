@@ -0,0 +1,1756 @@
+//! The launch logic of `on-the-gpu`: which wrappers and environment variables each GPU mode
+//! needs, building the command and environment to run a program with, and running it
+//! ([`Launch::exec`]). The CLI is a thin layer over this, which parses the options and prints what
+//! happens; [`config`] and [`env_file`] read the same files it does, to set up a [`Launch`] the
+//! same way.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::ffi::{CString, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+pub mod config;
+pub mod env_file;
+
+/// A way to get the command onto the GPU, or otherwise wrap it; the values of `--gpu`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GpuMode {
+    /// Run the command as-is. Can't be combined with other modes.
+    None,
+    /// Wrap the command in `pvkrun`.
+    Pvkrun,
+    /// PRIME render offload for NVIDIA's proprietary driver, as NVIDIA's `prime-run` does it: set
+    /// `__NV_PRIME_RENDER_OFFLOAD=1`, `__GLX_VENDOR_LIBRARY_NAME=nvidia` and
+    /// `__VK_LAYER_NV_optimus=NVIDIA_only`. The GLX one can be left out with `--no-nv-glx`, and
+    /// the Vulkan one with `--no-vk-layer-override`.
+    NvPrimeRun,
+    /// Set `DRI_PRIME=1`, PRIME render offload for Mesa drivers. This is the one to use for AMD
+    /// and Intel GPUs; it sets nothing NVIDIA-specific.
+    DriPrime,
+    /// Wrap the command in `switcherooctl launch --gpu N`, which asks switcheroo-control (as used
+    /// by GNOME) to set things up for the GPU.
+    Switcheroo,
+    /// Wrap the command in `mangohud`, for an FPS/frametime overlay.
+    Mangohud,
+    /// Wrap the command in `gamemoderun`, Feral's GameMode. This does nothing about which GPU is
+    /// used; it only tweaks the CPU governor & co. It sets no variables of its own, and when
+    /// combined with other wrappers it belongs outermost, e.g. `gamemoderun pvkrun space-game`.
+    Gamemode,
+    /// Run the command nested in `gamescope`, Valve's micro-compositor (e.g., for resolution
+    /// scaling). Its arguments are given with `--gamescope-args`.
+    Gamescope,
+}
+
+/// The mechanisms a mode might use to move the command onto the discrete GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offload {
+    /// Bumblebee-era wrappers, which render on the GPU and copy the frames over themselves.
+    Bumblebee,
+    /// PRIME render offload, which the drivers do, when asked to via the environment.
+    Prime,
+}
+
+impl GpuMode {
+    /// How this mode moves the command onto the discrete GPU, if it does; only one mode that does
+    /// makes sense at a time.
+    pub fn offload(self) -> Option<Offload> {
+        match self {
+            GpuMode::Pvkrun => Some(Offload::Bumblebee),
+            GpuMode::NvPrimeRun | GpuMode::DriPrime | GpuMode::Switcheroo => Some(Offload::Prime),
+            GpuMode::None | GpuMode::Mangohud | GpuMode::Gamemode | GpuMode::Gamescope => None,
+        }
+    }
+
+    /// The wrapper binary this mode runs the command under, if any.
+    pub fn wrapper(self) -> Option<&'static str> {
+        match self {
+            GpuMode::Pvkrun => Some("pvkrun"),
+            GpuMode::Switcheroo => Some("switcherooctl"),
+            GpuMode::Mangohud => Some("mangohud"),
+            GpuMode::Gamemode => Some("gamemoderun"),
+            GpuMode::Gamescope => Some("gamescope"),
+            GpuMode::None | GpuMode::NvPrimeRun | GpuMode::DriPrime => None,
+        }
+    }
+}
+
+impl std::fmt::Display for GpuMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no GpuMode values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// How much debug output `--vk-debug` turns on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum VkDebug {
+    /// `VK_LOADER_DEBUG=all`: the Vulkan loader logs the drivers and layers it finds and loads.
+    Loader,
+    /// As `loader`, plus Mesa's `MESA_DEBUG=1`, `LIBGL_DEBUG=verbose` and `EGL_LOG_LEVEL=debug`.
+    Driver,
+}
+
+/// Settings for `--gpu nv-prime-run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NvPrime {
+    /// The `__NV_PRIME_RENDER_OFFLOAD_PROVIDER` to set, e.g., `NVIDIA-G0`; if not set, the driver
+    /// picks the NVIDIA GPU.
+    pub provider: Option<String>,
+    /// Whether to set `__GLX_VENDOR_LIBRARY_NAME`, which only GLX (i.e., X11 OpenGL) looks at.
+    /// Even in a Wayland session, most games are X11 programs running under XWayland, and without
+    /// it their OpenGL stays on the integrated GPU.
+    pub glx: bool,
+    /// Whether to set `__VK_LAYER_NV_optimus=NVIDIA_only`, which hides the other GPUs from Vulkan
+    /// programs.
+    pub vk_layer_override: bool,
+}
+
+impl Default for NvPrime {
+    fn default() -> Self {
+        NvPrime {
+            provider: None,
+            glx: true,
+            vk_layer_override: true,
+        }
+    }
+}
+
+/// Settings for the GPU modes, besides which ones to use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModeOptions {
+    /// The GPU picked with `--gpu-index` or `--gpu-pci`, if any.
+    pub gpu: Option<ChosenGpu>,
+    /// The arguments for `gamescope`, e.g., the split-up `--gamescope-args`.
+    pub gamescope_args: Vec<OsString>,
+    /// The settings for `nv-prime-run`.
+    pub nv_prime: NvPrime,
+    /// The GPU for `switcheroo`, as its index in `switcherooctl list`; if not set, 1, the first
+    /// one that isn't the default.
+    pub switcheroo_gpu: Option<u32>,
+}
+
+/// A set of environment variables to set for the command, on top of the ones we were run with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtraEnv {
+    Mangohud,
+    /// PRIME render offload for NVIDIA's driver.
+    NvPrimeRun(NvPrime),
+    /// Select the GPU for Mesa drivers to render on: `1` for the one that isn't the default, or,
+    /// e.g., `pci-0000_01_00_0` for a specific one.
+    DriPrime(String),
+    VkDebug(VkDebug),
+    /// The window class for SDL to use.
+    WmClass(String),
+    /// The GPU modes the command was run with, for it (or overlays, scripts, …) to see.
+    Modes(Vec<GpuMode>),
+    /// Variables given by the user.
+    Vars(Vec<(String, OsString)>),
+}
+
+impl IntoIterator for &ExtraEnv {
+    type Item = (String, OsString);
+    type IntoIter = std::vec::IntoIter<(String, OsString)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let vars = match self {
+            ExtraEnv::Mangohud => vec![("MANGOHUD".to_owned(), "1".into())],
+            ExtraEnv::NvPrimeRun(nv_prime) => {
+                let mut vars = vec![("__NV_PRIME_RENDER_OFFLOAD".to_owned(), "1".into())];
+                if nv_prime.glx {
+                    vars.push(("__GLX_VENDOR_LIBRARY_NAME".to_owned(), "nvidia".into()));
+                }
+                if nv_prime.vk_layer_override {
+                    vars.push(("__VK_LAYER_NV_optimus".to_owned(), "NVIDIA_only".into()));
+                }
+                if let Some(provider) = &nv_prime.provider {
+                    vars.push((
+                        "__NV_PRIME_RENDER_OFFLOAD_PROVIDER".to_owned(),
+                        provider.into(),
+                    ));
+                }
+                vars
+            }
+            ExtraEnv::DriPrime(value) => vec![("DRI_PRIME".to_owned(), value.into())],
+            ExtraEnv::VkDebug(level) => {
+                let mut vars = vec![("VK_LOADER_DEBUG", "all")];
+                if let VkDebug::Driver = level {
+                    vars.extend([
+                        ("MESA_DEBUG", "1"),
+                        ("LIBGL_DEBUG", "verbose"),
+                        ("EGL_LOG_LEVEL", "debug"),
+                    ]);
+                }
+                vars.into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.into()))
+                    .collect()
+            }
+            ExtraEnv::WmClass(name) => vec![
+                ("SDL_VIDEO_X11_WMCLASS".to_owned(), name.into()),
+                ("SDL_VIDEO_WAYLAND_WMCLASS".to_owned(), name.into()),
+            ],
+            ExtraEnv::Modes(modes) => {
+                let modes = modes.iter().map(GpuMode::to_string).collect::<Vec<_>>();
+                vec![("ON_THE_GPU_MODE".to_owned(), modes.join(",").into())]
+            }
+            ExtraEnv::Vars(vars) => vars.clone(),
+        };
+        vars.into_iter()
+    }
+}
+
+/// A GPU, as seen by the kernel's DRM subsystem.
+struct Gpu {
+    /// The `N` of `/sys/class/drm/cardN`.
+    index: u32,
+    /// The PCI address, e.g., `0000:01:00.0`.
+    pci_address: Option<String>,
+    /// The PCI vendor ID, e.g., `0x10de` for NVIDIA.
+    vendor: Option<String>,
+}
+
+impl std::fmt::Display for Gpu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "card{} (PCI {}, vendor {})",
+            self.index,
+            self.pci_address.as_deref().unwrap_or("unknown"),
+            self.vendor.as_deref().unwrap_or("unknown"),
+        )
+    }
+}
+
+/// List the GPUs in `/sys/class/drm`, ordered by index.
+fn list_gpus() -> std::io::Result<Vec<Gpu>> {
+    let mut gpus = Vec::new();
+    for entry in std::fs::read_dir("/sys/class/drm")? {
+        let entry = entry?;
+        // This also skips the connectors, which are named like `card0-HDMI-A-1`.
+        let index = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("card"))
+            .and_then(|index| index.parse().ok())
+        {
+            Some(index) => index,
+            None => continue,
+        };
+        let device = entry.path().join("device");
+        let pci_address = std::fs::canonicalize(&device)
+            .ok()
+            .and_then(|path| path.file_name()?.to_str().map(str::to_owned));
+        let vendor = std::fs::read_to_string(device.join("vendor"))
+            .ok()
+            .map(|vendor| vendor.trim().to_owned());
+        gpus.push(Gpu {
+            index,
+            pci_address,
+            vendor,
+        });
+    }
+    gpus.sort_by_key(|gpu| gpu.index);
+    Ok(gpus)
+}
+
+/// PCI vendor ID of NVIDIA, as found in `/sys/class/drm/cardN/device/vendor`.
+const NVIDIA_VENDOR_ID: &str = "0x10de";
+
+/// Pick a GPU mode suitable for this system's GPUs. Fails if they can't be listed.
+pub fn detect_gpu_mode() -> std::io::Result<GpuMode> {
+    Ok(mode_for(&list_gpus()?))
+}
+
+/// Pick a GPU mode suitable for `gpus`. Only PCI GPUs count: cards without a PCI vendor are, e.g.,
+/// simpledrm's boot framebuffer, DisplayLink's evdi or vkms, none of which offload can target.
+fn mode_for(gpus: &[Gpu]) -> GpuMode {
+    let pci_gpus = gpus
+        .iter()
+        .filter(|gpu| gpu.vendor.is_some())
+        .collect::<Vec<_>>();
+    if pci_gpus.len() < 2 {
+        GpuMode::None
+    } else if pci_gpus
+        .iter()
+        .any(|gpu| gpu.vendor.as_deref() == Some(NVIDIA_VENDOR_ID))
+    {
+        GpuMode::NvPrimeRun
+    } else {
+        GpuMode::DriPrime
+    }
+}
+
+/// How a GPU was picked: by `--gpu-index` or by `--gpu-pci`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GpuSelector {
+    /// The `N` of `/sys/class/drm/cardN`.
+    Index(u32),
+    /// The PCI address, e.g., `0000:01:00.0`, or `01:00.0` (in the first PCI domain).
+    Pci(String),
+}
+
+impl GpuSelector {
+    fn matches(&self, gpu: &Gpu) -> bool {
+        match self {
+            GpuSelector::Index(index) => gpu.index == *index,
+            GpuSelector::Pci(address) => {
+                let address = address.to_ascii_lowercase();
+                let address = if address.matches(':').count() == 1 {
+                    format!("0000:{}", address)
+                } else {
+                    address
+                };
+                gpu.pci_address.as_deref() == Some(address.as_str())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for GpuSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpuSelector::Index(index) => write!(f, "with index {}", index),
+            GpuSelector::Pci(address) => write!(f, "at PCI address {}", address),
+        }
+    }
+}
+
+/// A GPU picked with `--gpu-index` or `--gpu-pci`, and how each mode is told to use it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChosenGpu {
+    /// The `N` of `/sys/class/drm/cardN`.
+    pub index: u32,
+    /// The value of `DRI_PRIME` that picks it, e.g., `pci-0000_01_00_0`, if we know its PCI
+    /// address. (A bare `DRI_PRIME=N` doesn't mean card `N`: `1` is "not the default GPU", and `0`
+    /// turns offload off.)
+    pub dri_prime: Option<String>,
+    /// The `__NV_PRIME_RENDER_OFFLOAD_PROVIDER` that picks it, e.g., `NVIDIA-G0`, if it's NVIDIA's.
+    pub nv_provider: Option<String>,
+}
+
+/// Find the GPU that `selector` picks, and check that `modes` can use it. If there's no such GPU,
+/// the error lists the ones there are.
+pub fn select_gpu(selector: &GpuSelector, modes: &[GpuMode]) -> anyhow::Result<ChosenGpu> {
+    if modes == [GpuMode::None] {
+        bail!("--gpu none runs the command as-is, so it can't be combined with picking a GPU.");
+    }
+    if modes.contains(&GpuMode::Switcheroo) {
+        bail!(
+            "switcherooctl numbers the GPUs its own way (see `switcherooctl list`), not as \
+             /sys/class/drm does; pick its GPU with --switcheroo-gpu instead."
+        );
+    }
+    if let Some(mode) = modes
+        .iter()
+        .find(|mode| mode.offload() == Some(Offload::Bumblebee))
+    {
+        bail!(
+            "Picking a GPU uses PRIME render offload, so it can't be combined with --gpu {}.",
+            mode
+        );
+    }
+    let gpus = list_gpus().context("failed to list GPUs")?;
+    let Some(gpu) = gpus.iter().find(|gpu| selector.matches(gpu)) else {
+        let mut msg = format!("There's no GPU {}.", selector);
+        if gpus.is_empty() {
+            msg.push_str(" No GPUs were found in /sys/class/drm.");
+        } else {
+            msg.push_str(" Available GPUs:");
+            for gpu in &gpus {
+                msg.push_str(&format!("\n  {}", gpu));
+            }
+        }
+        bail!(msg)
+    };
+    let is_nvidia = |gpu: &Gpu| gpu.vendor.as_deref() == Some(NVIDIA_VENDOR_ID);
+    let chosen = ChosenGpu {
+        index: gpu.index,
+        // Mesa's `pci-DDDD_BB_SS_F` form.
+        dri_prime: gpu
+            .pci_address
+            .as_ref()
+            .map(|address| format!("pci-{}", address.replace([':', '.'], "_"))),
+        // The driver numbers its GPUs in the same (PCI) order as the DRM cards.
+        nv_provider: is_nvidia(gpu).then(|| {
+            let rank = gpus
+                .iter()
+                .filter(|other| is_nvidia(other) && other.index < gpu.index)
+                .count();
+            format!("NVIDIA-G{}", rank)
+        }),
+    };
+    if modes.contains(&GpuMode::NvPrimeRun) {
+        if chosen.nv_provider.is_none() {
+            bail!(
+                "--gpu nv-prime-run needs an NVIDIA GPU, and {} isn't one.",
+                gpu
+            );
+        }
+    } else if chosen.dri_prime.is_none() {
+        bail!(
+            "Can't tell the PCI address of card{}, which DRI_PRIME needs to pick it.",
+            gpu.index
+        );
+    }
+    Ok(chosen)
+}
+
+/// Check that the given GPU modes make sense together.
+pub fn validate(modes: &[GpuMode]) -> anyhow::Result<()> {
+    for (idx, mode) in modes.iter().enumerate() {
+        if modes[..idx].contains(mode) {
+            bail!("--gpu {} was given more than once.", mode);
+        }
+    }
+    if modes.len() > 1 && modes.contains(&GpuMode::None) {
+        bail!("--gpu none can't be combined with other GPU modes.");
+    }
+    let offload = modes
+        .iter()
+        .filter(|mode| mode.offload().is_some())
+        .collect::<Vec<_>>();
+    if let [first, second, ..] = offload[..] {
+        if first.offload() != second.offload() {
+            bail!(
+                "--gpu {} and --gpu {} can't be combined: one is a Bumblebee-era wrapper, the \
+                 other uses PRIME render offload.",
+                first,
+                second,
+            );
+        }
+        bail!(
+            "--gpu {} and --gpu {} both put the command on the GPU; pick one.",
+            first,
+            second,
+        );
+    }
+    Ok(())
+}
+
+/// Wrap `command` in the wrappers for each of `modes`, the first mode being the outermost, and
+/// collect the environment variables they need, with `options` for the modes that take any.
+pub fn build_command(
+    modes: &[GpuMode],
+    options: &ModeOptions,
+    command: &[OsString],
+) -> (Vec<OsString>, Vec<ExtraEnv>) {
+    let gpu = options.gpu.as_ref();
+    let dri_prime = gpu
+        .and_then(|gpu| gpu.dri_prime.clone())
+        .unwrap_or_else(|| "1".to_owned());
+    let mut cmd_to_run = command.to_vec();
+    let mut extra_env = Vec::new();
+    let mut used_gpu = false;
+    for mode in modes.iter().rev() {
+        match mode {
+            GpuMode::None => {}
+            GpuMode::Pvkrun => cmd_to_run = wrap_command(["pvkrun"], &cmd_to_run),
+            GpuMode::NvPrimeRun => {
+                let mut nv_prime = options.nv_prime.clone();
+                if let Some(provider) = gpu.and_then(|gpu| gpu.nv_provider.clone()) {
+                    nv_prime.provider = Some(provider);
+                }
+                extra_env.push(ExtraEnv::NvPrimeRun(nv_prime));
+                used_gpu = true;
+            }
+            GpuMode::DriPrime => {
+                extra_env.push(ExtraEnv::DriPrime(dri_prime.clone()));
+                used_gpu = true;
+            }
+            GpuMode::Switcheroo => {
+                // switcherooctl lists the default GPU first, so 1 is the first other one.
+                let index = options.switcheroo_gpu.unwrap_or(1).to_string();
+                cmd_to_run =
+                    wrap_command(["switcherooctl", "launch", "--gpu", &index], &cmd_to_run);
+                used_gpu = true;
+            }
+            GpuMode::Mangohud => {
+                cmd_to_run = wrap_command(["mangohud"], &cmd_to_run);
+                extra_env.push(ExtraEnv::Mangohud);
+            }
+            GpuMode::Gamemode => cmd_to_run = wrap_command(["gamemoderun"], &cmd_to_run),
+            GpuMode::Gamescope => {
+                let mut wrapper = vec![OsString::from("gamescope")];
+                wrapper.extend(options.gamescope_args.iter().cloned());
+                // gamescope needs a `--` between its own arguments and the command.
+                if wrapper.last().map(OsString::as_os_str) != Some(OsStr::new("--")) {
+                    wrapper.push(OsString::from("--"));
+                }
+                cmd_to_run = wrap_command(wrapper, &cmd_to_run);
+            }
+        }
+    }
+    // We went innermost-first; put the sets back in the order the modes were given.
+    extra_env.reverse();
+    if gpu.is_some() && !used_gpu {
+        extra_env.push(ExtraEnv::DriPrime(dri_prime));
+    }
+    (cmd_to_run, extra_env)
+}
+
+/// Everything that goes into launching a command: the GPU modes, and what else wraps the command or
+/// adds to its environment. [`Launch::build`] puts it together, and [`Launch::exec`] runs it, as
+/// the CLI does.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Launch {
+    /// The GPU modes, the first one being the outermost.
+    pub modes: Vec<GpuMode>,
+    /// Settings for the modes.
+    pub options: ModeOptions,
+    /// A tracer to run the command under, right around it.
+    pub tracer: Option<Tracer>,
+    /// A sandbox to run the command in, outside all the modes' wrappers.
+    pub sandbox: Option<Sandbox>,
+    /// More arguments for the sandbox.
+    pub sandbox_args: Vec<OsString>,
+    /// Debug output to turn on, for the Vulkan loader and maybe the drivers.
+    pub vk_debug: Option<VkDebug>,
+    /// The window class for SDL to use.
+    pub wm_class: Option<String>,
+    /// Sets of variables given by the user, from least to most specific: e.g., the config file's,
+    /// then the env files', then `--env`. These win over the ones above.
+    pub vars: Vec<Vec<(String, OsString)>>,
+}
+
+impl Launch {
+    /// The binaries that running `command` takes: the wrappers' (outermost first), then the
+    /// command's own, if there is one.
+    pub fn binaries<'a>(&'a self, command: &'a [OsString]) -> Vec<&'a OsStr> {
+        let sandbox = self.sandbox.map(Sandbox::binary);
+        let wrappers = self.modes.iter().filter_map(|mode| mode.wrapper());
+        let tracer = self.tracer.map(Tracer::binary);
+        let mut binaries: Vec<&OsStr> = sandbox
+            .into_iter()
+            .chain(wrappers)
+            .chain(tracer)
+            .map(OsStr::new)
+            .collect();
+        binaries.extend(command.first().map(OsString::as_os_str));
+        binaries
+    }
+
+    /// Wrap `command` for running, and collect the environment variables to set for it, later sets
+    /// winning over earlier ones.
+    ///
+    /// The sandbox goes outermost, then the modes' wrappers, then the tracer. `firejail` (and
+    /// `bwrap`, where it's setuid) makes glibc drop `LD_PRELOAD`, which `gamemoderun` and
+    /// `mangohud` work by, so their wrappers have to run inside it.
+    pub fn build(&self, command: &[OsString]) -> (Vec<OsString>, Vec<ExtraEnv>) {
+        let command = match self.tracer {
+            Some(tracer) => tracer.wrap(command),
+            None => command.to_vec(),
+        };
+        let (mut cmd_to_run, mut extra_env) = build_command(&self.modes, &self.options, &command);
+        if let Some(sandbox) = self.sandbox {
+            let mut sandbox_args = Vec::new();
+            // firejail's seccomp filter blocks `ptrace`, unless told otherwise.
+            if sandbox == Sandbox::Firejail && self.tracer.is_some() {
+                sandbox_args.push(OsString::from("--allow-debuggers"));
+            }
+            sandbox_args.extend(self.sandbox_args.iter().cloned());
+            cmd_to_run = sandbox.wrap(&sandbox_args, &cmd_to_run);
+        }
+        // `none` runs the command as-is.
+        if self.modes != [GpuMode::None] {
+            extra_env.push(ExtraEnv::Modes(self.modes.clone()));
+        }
+        if let Some(level) = self.vk_debug {
+            extra_env.push(ExtraEnv::VkDebug(level));
+        }
+        if let Some(name) = &self.wm_class {
+            extra_env.push(ExtraEnv::WmClass(name.clone()));
+        }
+        extra_env.extend(
+            self.vars
+                .iter()
+                .filter(|vars| !vars.is_empty())
+                .map(|vars| ExtraEnv::Vars(vars.clone())),
+        );
+        (cmd_to_run, extra_env)
+    }
+
+    /// Check that the wrappers that need checking before running anything are in PATH: the
+    /// tracer, the sandbox, and `switcherooctl`. (The others fail clearly enough when run.)
+    pub fn check_installed(&self) -> anyhow::Result<()> {
+        if let Some(tracer) = self.tracer {
+            if find_in_path(tracer.binary()).is_none() {
+                bail!(
+                    "--trace {} needs `{}`, which isn't in PATH.",
+                    tracer.binary(),
+                    tracer.binary()
+                );
+            }
+        }
+        if let Some(sandbox) = self.sandbox {
+            if find_in_path(sandbox.binary()).is_none() {
+                bail!(
+                    "--sandbox {} needs `{}`, which isn't in PATH.",
+                    sandbox.binary(),
+                    sandbox.binary()
+                );
+            }
+        }
+        if self.modes.contains(&GpuMode::Switcheroo) && find_in_path("switcherooctl").is_none() {
+            bail!(
+                "--gpu switcheroo needs `switcherooctl` (from switcheroo-control), which isn't in \
+                 PATH."
+            );
+        }
+        Ok(())
+    }
+
+    /// Run `command`, as [`Launch::build`] wraps it, in place of this process, with `run`'s
+    /// settings. What happens on the way is passed to `report`, for the caller to show.
+    ///
+    /// This only returns on failure. If the binary to run doesn't exist, the error is a
+    /// [`CommandNotFound`], which a shell would exit with status 127 for.
+    pub fn exec(
+        &self,
+        command: &[OsString],
+        run: &RunOptions,
+        mut report: impl FnMut(Report<'_>),
+    ) -> anyhow::Result<Infallible> {
+        if command.is_empty() {
+            bail!("Need at least 1 argument for the command to run.");
+        }
+        self.check_installed()?;
+        let (cmd_to_run, extra_env) = self.build(command);
+        let (env, overrides) = run.env(&extra_env)?;
+        if !overrides.is_empty() {
+            report(Report::Overrides(&overrides));
+        }
+        if let Some(dir) = &run.cwd {
+            std::env::set_current_dir(dir)
+                .with_context(|| format!("failed to change directory to {}", dir.display()))?;
+        }
+        if run.no_stdin {
+            use std::os::fd::AsRawFd;
+
+            let dev_null = std::fs::File::open("/dev/null").context("failed to open /dev/null")?;
+            nix::unistd::dup2(dev_null.as_raw_fd(), nix::libc::STDIN_FILENO)
+                .context("failed to replace stdin with /dev/null")?;
+        }
+        for pre_cmd in &run.pre_cmds {
+            let status = run_pre_cmd(pre_cmd)?;
+            if !status.success() {
+                if !run.ignore_pre_cmd_failure {
+                    bail!(
+                        "--pre-cmd {:?} failed ({}), so not running the command.",
+                        pre_cmd,
+                        status
+                    );
+                }
+                report(Report::PreCmdFailed { pre_cmd, status });
+            }
+        }
+        // These are inherited over the exec. They're set after the pre-cmds, which don't need them.
+        if let Some(nice) = run.nice {
+            if let Err(err) = set_nice(nice) {
+                report(Report::NiceFailed { nice, err });
+            }
+        }
+        if run.sched_rr {
+            if let Err(err) = set_sched_rr() {
+                report(Report::SchedRrFailed(err));
+            }
+        }
+        report(Report::Exec {
+            command: &cmd_to_run,
+            extra_env: &extra_env,
+            env: &env,
+        });
+
+        let to_exec_args = build_args(&cmd_to_run)?;
+        let env = build_env(env)?;
+        let Err(err) = nix::unistd::execvpe(&to_exec_args[0], &to_exec_args, &env);
+        if err == nix::errno::Errno::ENOENT {
+            return Err(CommandNotFound {
+                binary: cmd_to_run[0].clone(),
+                path: std::env::var_os("PATH"),
+            }
+            .into());
+        }
+        Err(err).with_context(|| format!("failed to exec `{}`", cmd_to_run[0].to_string_lossy()))
+    }
+}
+
+/// How [`Launch::exec`] runs the command, besides what wraps it and its environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    /// The directory to run the command in; `PWD` is set to it, too.
+    pub cwd: Option<PathBuf>,
+    /// Whether to start the environment from scratch, rather than from ours.
+    pub clear_env: bool,
+    /// With `clear_env`, the variables of ours to keep, if they're set.
+    pub keep_env: Vec<String>,
+    /// Whether to connect the command's stdin to /dev/null.
+    pub no_stdin: bool,
+    /// Shell commands to run, in order, before the command.
+    pub pre_cmds: Vec<OsString>,
+    /// Whether to run the command even if one of `pre_cmds` fails.
+    pub ignore_pre_cmd_failure: bool,
+    /// The niceness to run the command at.
+    pub nice: Option<i32>,
+    /// Whether to run the command with the `SCHED_RR` scheduling policy, at its lowest priority.
+    pub sched_rr: bool,
+}
+
+impl RunOptions {
+    /// The directory the command runs in: `cwd`, made absolute, or ours.
+    pub fn dir(&self) -> std::io::Result<PathBuf> {
+        let current_dir = std::env::current_dir()?;
+        Ok(match &self.cwd {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir,
+        })
+    }
+
+    /// The environment the command starts from, before any [`ExtraEnv`]: ours, or only the
+    /// `keep_env` variables, with `PWD` set to `cwd`, if it's given, as a shell's `cd` would.
+    pub fn base_env(&self) -> anyhow::Result<BTreeMap<OsString, OsString>> {
+        let mut env = inherited_env(self.clear_env, &self.keep_env);
+        if self.cwd.is_some() {
+            let dir = self.dir().context("failed to get the current directory")?;
+            env.insert("PWD".into(), dir.into());
+        }
+        Ok(env)
+    }
+
+    /// The command's environment: `extra_env` on top of [`RunOptions::base_env`], and the
+    /// variables that were overridden along the way (see [`merge_env`]).
+    pub fn env(
+        &self,
+        extra_env: &[ExtraEnv],
+    ) -> anyhow::Result<(BTreeMap<OsString, OsString>, Vec<Override>)> {
+        Ok(merge_env(self.base_env()?, extra_env))
+    }
+}
+
+/// Something [`Launch::exec`] did or ran into on the way, for the caller to show.
+#[derive(Debug)]
+pub enum Report<'a> {
+    /// Variables that a set of variables overrode with a different value.
+    Overrides(&'a [Override]),
+    /// A pre-command failed; the command is run anyway, as `ignore_pre_cmd_failure` asks.
+    PreCmdFailed {
+        pre_cmd: &'a OsStr,
+        status: std::process::ExitStatus,
+    },
+    /// Setting the niceness failed, likely for lack of privileges; the command is run anyway.
+    NiceFailed { nice: i32, err: nix::Error },
+    /// Switching to `SCHED_RR` failed, likely for lack of privileges; the command is run anyway.
+    SchedRrFailed(nix::Error),
+    /// The command is about to be run, with `env`, which is `extra_env` on top of the base
+    /// environment.
+    Exec {
+        command: &'a [OsString],
+        extra_env: &'a [ExtraEnv],
+        env: &'a BTreeMap<OsString, OsString>,
+    },
+}
+
+/// The binary [`Launch::exec`] was to run doesn't exist. The search path is often what's wrong,
+/// when run from a launcher, so this shows it.
+#[derive(Debug)]
+pub struct CommandNotFound {
+    /// The binary, as given.
+    pub binary: OsString,
+    /// Our `PATH`, which it was looked up in, unless it has a slash in it.
+    pub path: Option<OsString>,
+}
+
+impl std::fmt::Display for CommandNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let binary = self.binary.to_string_lossy();
+        if binary.contains('/') {
+            return write!(f, "'{}' not found", binary);
+        }
+        write!(f, "'{}' not found in PATH", binary)?;
+        match &self.path {
+            Some(path) => write!(f, "\nPATH={}", path.to_string_lossy()),
+            None => write!(f, "\n(PATH is not set)"),
+        }
+    }
+}
+
+impl std::error::Error for CommandNotFound {}
+
+/// Run a pre-command with `sh`, and wait for it.
+fn run_pre_cmd(pre_cmd: &OsStr) -> anyhow::Result<std::process::ExitStatus> {
+    std::process::Command::new("sh")
+        .arg("-c")
+        .arg(pre_cmd)
+        .status()
+        .with_context(|| format!("failed to run --pre-cmd {:?}", pre_cmd))
+}
+
+/// Set this process's niceness.
+fn set_nice(nice: i32) -> nix::Result<()> {
+    // SAFETY: this takes no pointers; it's only unsafe as all FFI is.
+    let result = unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, nice) };
+    nix::errno::Errno::result(result).map(drop)
+}
+
+/// Switch this process to the `SCHED_RR` scheduling policy, at its lowest priority.
+fn set_sched_rr() -> nix::Result<()> {
+    // SAFETY: `param` outlives the call, which only reads it.
+    let result = unsafe {
+        let param = nix::libc::sched_param {
+            sched_priority: nix::libc::sched_get_priority_min(nix::libc::SCHED_RR),
+        };
+        nix::libc::sched_setscheduler(0, nix::libc::SCHED_RR, &param)
+    };
+    nix::errno::Errno::result(result).map(drop)
+}
+
+/// Read the arguments in a `--command-file` (or stdin, for `-`).
+pub fn read_command_file(path: &Path) -> anyhow::Result<Vec<OsString>> {
+    use std::io::Read;
+
+    let contents = if path == Path::new("-") {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .context("failed to read the command from stdin")?;
+        contents
+    } else {
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?
+    };
+    Ok(split_command_file(&contents))
+}
+
+/// Split the contents of a `--command-file` into arguments: at NUL bytes, if it has any, or else
+/// at newlines.
+fn split_command_file(contents: &[u8]) -> Vec<OsString> {
+    let separator = if contents.contains(&0) { 0 } else { b'\n' };
+    let mut args = contents
+        .split(|&byte| byte == separator)
+        .map(|arg| match separator {
+            // Windows line endings.
+            b'\n' => arg.strip_suffix(b"\r").unwrap_or(arg),
+            _ => arg,
+        })
+        .map(|arg| OsStr::from_bytes(arg).to_owned())
+        .collect::<Vec<_>>();
+    // A trailing separator doesn't start another argument.
+    if args.last().is_some_and(|arg| arg.is_empty()) {
+        args.pop();
+    }
+    args
+}
+
+/// Replace the `%command%` in `command` with `real_command` (the `--command` arguments), as Steam
+/// does with its launch options. Without a `%command%`, `command` is returned as-is, and there must
+/// be no `real_command`.
+pub fn expand_command_placeholder(
+    mut command: Vec<OsString>,
+    real_command: Vec<OsString>,
+) -> anyhow::Result<Vec<OsString>> {
+    let Some(idx) = command.iter().position(|arg| arg == "%command%") else {
+        if !real_command.is_empty() {
+            bail!("--command only makes sense with %command% in the command.");
+        }
+        return Ok(command);
+    };
+    if command[idx + 1..].iter().any(|arg| arg == "%command%") {
+        bail!("%command% can only be in the command once.");
+    }
+    if real_command.is_empty() {
+        bail!("The command has %command% in it, but there's no --command to put there.");
+    }
+    command.splice(idx..=idx, real_command);
+    Ok(command)
+}
+
+/// Returns `command`, prefixed with `wrapper` (the wrapper binary, and its arguments).
+pub fn wrap_command<S: Into<OsString>>(
+    wrapper: impl IntoIterator<Item = S>,
+    command: &[OsString],
+) -> Vec<OsString> {
+    let mut wrapped = wrapper.into_iter().map(Into::into).collect::<Vec<_>>();
+    wrapped.extend(command.iter().cloned());
+    wrapped
+}
+
+/// A tracer for `--trace`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Tracer {
+    /// `strace -f`: system calls, of the command and its children.
+    Strace,
+    /// `ltrace -f`: library calls, of the command and its children.
+    Ltrace,
+}
+
+impl Tracer {
+    pub fn binary(self) -> &'static str {
+        match self {
+            Tracer::Strace => "strace",
+            Tracer::Ltrace => "ltrace",
+        }
+    }
+
+    /// The command to run `command` under this tracer, following its children.
+    pub fn wrap(self, command: &[OsString]) -> Vec<OsString> {
+        wrap_command([self.binary(), "-f"], command)
+    }
+}
+
+/// A sandbox for `--sandbox`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Sandbox {
+    /// `firejail`, with its default profile for the command. That keeps `/dev/dri` and NVIDIA's
+    /// device nodes, unless it's told otherwise (e.g., by `--no3d` or `--private-dev`).
+    Firejail,
+    /// `bwrap` (bubblewrap), with a read-only view of `/`, and a minimal `/dev` with the GPUs'
+    /// device nodes (`/dev/dri` and NVIDIA's `/dev/nvidia*`) bound in.
+    Bwrap,
+}
+
+impl Sandbox {
+    pub fn binary(self) -> &'static str {
+        match self {
+            Sandbox::Firejail => "firejail",
+            Sandbox::Bwrap => "bwrap",
+        }
+    }
+
+    /// The command to run `command` in this sandbox, with the user's `extra_args`.
+    pub fn wrap(self, extra_args: &[OsString], command: &[OsString]) -> Vec<OsString> {
+        let mut wrapper = vec![OsString::from(self.binary())];
+        if let Sandbox::Bwrap = self {
+            let args = ["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"];
+            wrapper.extend(args.map(OsString::from));
+            for node in gpu_device_nodes() {
+                wrapper.extend(["--dev-bind".into(), node.clone().into(), node.into()]);
+            }
+        }
+        wrapper.extend_from_slice(extra_args);
+        wrapper.push("--".into());
+        wrap_command(wrapper, command)
+    }
+}
+
+/// The device nodes that GPU drivers open: `/dev/dri`, and NVIDIA's `/dev/nvidia*`, if there are
+/// any.
+pub fn gpu_device_nodes() -> Vec<PathBuf> {
+    let mut nodes = vec![PathBuf::from("/dev/dri")];
+    if let Ok(entries) = std::fs::read_dir("/dev") {
+        let mut nvidia_nodes = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().as_bytes().starts_with(b"nvidia"))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        nvidia_nodes.sort();
+        nodes.extend(nvidia_nodes);
+    }
+    nodes.retain(|node| node.exists());
+    nodes
+}
+
+/// Find `binary` in `PATH`, as `execvp` would.
+pub fn find_in_path(binary: impl AsRef<OsStr>) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let binary = binary.as_ref();
+    let is_executable = |candidate: &PathBuf| {
+        candidate
+            .metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    // `execvp` doesn't search `PATH` for anything with a slash in it.
+    if binary.as_bytes().contains(&b'/') {
+        return Some(PathBuf::from(binary)).filter(is_executable);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| {
+            // An empty entry means the current directory.
+            let dir = if dir.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                dir
+            };
+            dir.join(binary)
+        })
+        .find(is_executable)
+}
+
+/// The environment the command starts from: our own, or, with `clear_env`, only the variables in
+/// `keep_env`.
+pub fn inherited_env(clear_env: bool, keep_env: &[String]) -> BTreeMap<OsString, OsString> {
+    std::env::vars_os()
+        .filter(|(key, _)| !clear_env || keep_env.iter().any(|keep| key == keep.as_str()))
+        .collect()
+}
+
+/// A variable that [`merge_env`] set to a different value than it already had.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    pub key: String,
+    pub old_value: OsString,
+    pub new_value: OsString,
+}
+
+/// Set the variables in `extra_env` on top of `env`. A variable set by a later set overrides one
+/// set by an earlier set. Since that might not be what the user expected, this also returns the
+/// variables overridden with a different value, for a warning.
+pub fn merge_env(
+    mut env: BTreeMap<OsString, OsString>,
+    extra_env: &[ExtraEnv],
+) -> (BTreeMap<OsString, OsString>, Vec<Override>) {
+    let mut overrides = Vec::new();
+    for (key, value) in extra_env.iter().flatten() {
+        if let Some(old_value) = env.insert(OsString::from(&key), value.clone()) {
+            if old_value != value {
+                overrides.push(Override {
+                    key,
+                    old_value,
+                    new_value: value,
+                });
+            }
+        }
+    }
+    (env, overrides)
+}
+
+/// Build the environment for `execvpe`, from `env` (e.g., from [`merge_env`]).
+pub fn build_env(env: BTreeMap<OsString, OsString>) -> anyhow::Result<Vec<CString>> {
+    env.into_iter()
+        .map(|(key, value)| {
+            let mut entry = key.clone();
+            entry.push("=");
+            entry.push(value);
+            os_str_to_cstring(&entry, || {
+                format!("environment variable {}", key.to_string_lossy())
+            })
+        })
+        .collect()
+}
+
+/// Build the arguments for `execvpe`, from the command.
+pub fn build_args(cmd: &[OsString]) -> anyhow::Result<Vec<CString>> {
+    cmd.iter()
+        .map(|arg| os_str_to_cstring(arg, || format!("argument {:?}", arg)))
+        .collect()
+}
+
+/// Convert `s` for passing to `execvpe`; `what` says what it is, for the error if it has a NUL byte
+/// in it.
+fn os_str_to_cstring(s: &OsStr, what: impl FnOnce() -> String) -> anyhow::Result<CString> {
+    CString::new(s.as_bytes()).with_context(|| format!("{} contains a NUL byte", what()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    /// The variables in `extra_env`, in the order they're set.
+    fn vars(extra_env: &[ExtraEnv]) -> Vec<(String, OsString)> {
+        extra_env.iter().flatten().collect()
+    }
+
+    fn var(key: &str, value: &str) -> (String, OsString) {
+        (key.to_owned(), value.into())
+    }
+
+    fn run(modes: &[GpuMode], options: &ModeOptions) -> (Vec<OsString>, Vec<(String, OsString)>) {
+        let (cmd_to_run, extra_env) =
+            build_command(modes, options, &os_strings(&["space-game", "--fast"]));
+        (cmd_to_run, vars(&extra_env))
+    }
+
+    #[test]
+    fn none() {
+        let (cmd, env) = run(&[GpuMode::None], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["space-game", "--fast"]));
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn pvkrun() {
+        let (cmd, env) = run(&[GpuMode::Pvkrun], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["pvkrun", "space-game", "--fast"]));
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn switcheroo() {
+        let (cmd, env) = run(&[GpuMode::Switcheroo], &ModeOptions::default());
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "switcherooctl",
+                "launch",
+                "--gpu",
+                "1",
+                "space-game",
+                "--fast"
+            ]),
+        );
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn switcheroo_with_gpu() {
+        let options = ModeOptions {
+            switcheroo_gpu: Some(2),
+            ..ModeOptions::default()
+        };
+        let (cmd, env) = run(&[GpuMode::Switcheroo], &options);
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "switcherooctl",
+                "launch",
+                "--gpu",
+                "2",
+                "space-game",
+                "--fast"
+            ]),
+        );
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn gamescope() {
+        let options = ModeOptions {
+            gamescope_args: os_strings(&["-W", "1920"]),
+            ..ModeOptions::default()
+        };
+        let (cmd, env) = run(&[GpuMode::Gamescope], &options);
+        assert_eq!(
+            cmd,
+            os_strings(&["gamescope", "-W", "1920", "--", "space-game", "--fast"]),
+        );
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn gamescope_args_ending_in_separator() {
+        let options = ModeOptions {
+            gamescope_args: os_strings(&["-W", "1920", "--"]),
+            ..ModeOptions::default()
+        };
+        let (cmd, _) = run(&[GpuMode::Gamescope], &options);
+        assert_eq!(
+            cmd,
+            os_strings(&["gamescope", "-W", "1920", "--", "space-game", "--fast"]),
+        );
+    }
+
+    #[test]
+    fn nv_prime_run() {
+        let (cmd, env) = run(&[GpuMode::NvPrimeRun], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["space-game", "--fast"]));
+        assert_eq!(
+            env,
+            [
+                var("__NV_PRIME_RENDER_OFFLOAD", "1"),
+                var("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+                var("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+            ],
+        );
+    }
+
+    #[test]
+    fn nv_prime_run_without_glx() {
+        let options = ModeOptions {
+            nv_prime: NvPrime {
+                glx: false,
+                ..NvPrime::default()
+            },
+            ..ModeOptions::default()
+        };
+        let (_, env) = run(&[GpuMode::NvPrimeRun], &options);
+        assert_eq!(
+            env,
+            [
+                var("__NV_PRIME_RENDER_OFFLOAD", "1"),
+                var("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+            ],
+        );
+    }
+
+    #[test]
+    fn nv_prime_run_without_vk_layer_override() {
+        let options = ModeOptions {
+            nv_prime: NvPrime {
+                vk_layer_override: false,
+                ..NvPrime::default()
+            },
+            ..ModeOptions::default()
+        };
+        let (_, env) = run(&[GpuMode::NvPrimeRun], &options);
+        assert_eq!(
+            env,
+            [
+                var("__NV_PRIME_RENDER_OFFLOAD", "1"),
+                var("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+            ],
+        );
+    }
+
+    #[test]
+    fn nv_prime_run_with_provider() {
+        let options = ModeOptions {
+            nv_prime: NvPrime {
+                provider: Some("NVIDIA-G1".to_owned()),
+                ..NvPrime::default()
+            },
+            ..ModeOptions::default()
+        };
+        let (_, env) = run(&[GpuMode::NvPrimeRun], &options);
+        assert_eq!(
+            env,
+            [
+                var("__NV_PRIME_RENDER_OFFLOAD", "1"),
+                var("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+                var("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+                var("__NV_PRIME_RENDER_OFFLOAD_PROVIDER", "NVIDIA-G1"),
+            ],
+        );
+    }
+
+    #[test]
+    fn nv_prime_run_with_chosen_gpu() {
+        let options = ModeOptions {
+            gpu: Some(ChosenGpu {
+                index: 1,
+                dri_prime: Some("pci-0000_01_00_0".to_owned()),
+                nv_provider: Some("NVIDIA-G0".to_owned()),
+            }),
+            ..ModeOptions::default()
+        };
+        let (cmd, env) = run(&[GpuMode::NvPrimeRun], &options);
+        assert_eq!(cmd, os_strings(&["space-game", "--fast"]));
+        // Not `DRI_PRIME`: the GPU is NVIDIA's, and nv-prime-run picked it.
+        assert_eq!(
+            env,
+            [
+                var("__NV_PRIME_RENDER_OFFLOAD", "1"),
+                var("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+                var("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+                var("__NV_PRIME_RENDER_OFFLOAD_PROVIDER", "NVIDIA-G0"),
+            ],
+        );
+    }
+
+    #[test]
+    fn dri_prime() {
+        let (cmd, env) = run(&[GpuMode::DriPrime], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["space-game", "--fast"]));
+        // Nothing NVIDIA-specific.
+        assert_eq!(env, [var("DRI_PRIME", "1")]);
+    }
+
+    #[test]
+    fn mangohud() {
+        let (cmd, env) = run(&[GpuMode::Mangohud], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["mangohud", "space-game", "--fast"]));
+        assert_eq!(env, [var("MANGOHUD", "1")]);
+    }
+
+    #[test]
+    fn gamemode() {
+        let (cmd, env) = run(&[GpuMode::Gamemode], &ModeOptions::default());
+        assert_eq!(cmd, os_strings(&["gamemoderun", "space-game", "--fast"]));
+        assert_eq!(env, []);
+    }
+
+    #[test]
+    fn nested_modes() {
+        let modes = [GpuMode::Gamemode, GpuMode::Mangohud, GpuMode::Pvkrun];
+        let (cmd, env) = run(&modes, &ModeOptions::default());
+        assert_eq!(
+            cmd,
+            os_strings(&["gamemoderun", "mangohud", "pvkrun", "space-game", "--fast"]),
+        );
+        assert_eq!(env, [var("MANGOHUD", "1")]);
+    }
+
+    #[test]
+    fn first_mode_is_outermost() {
+        let (cmd, _) = run(
+            &[GpuMode::Gamemode, GpuMode::Pvkrun],
+            &ModeOptions::default(),
+        );
+        assert_eq!(
+            cmd,
+            os_strings(&["gamemoderun", "pvkrun", "space-game", "--fast"])
+        );
+
+        let modes = [GpuMode::Gamescope, GpuMode::Gamemode, GpuMode::Switcheroo];
+        let (cmd, _) = run(&modes, &ModeOptions::default());
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "gamescope",
+                "--",
+                "gamemoderun",
+                "switcherooctl",
+                "launch",
+                "--gpu",
+                "1",
+                "space-game",
+                "--fast",
+            ]),
+        );
+    }
+
+    fn validate_error(modes: &[GpuMode]) -> String {
+        validate(modes).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn validate_accepts_wrappers_around_offload() {
+        let modes = [GpuMode::Gamemode, GpuMode::Mangohud, GpuMode::DriPrime];
+        assert!(validate(&modes).is_ok());
+        assert!(validate(&[GpuMode::None]).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_none_with_others() {
+        assert_eq!(
+            validate_error(&[GpuMode::None, GpuMode::Mangohud]),
+            "--gpu none can't be combined with other GPU modes.",
+        );
+    }
+
+    #[test]
+    fn validate_rejects_duplicates() {
+        assert_eq!(
+            validate_error(&[GpuMode::Mangohud, GpuMode::Gamemode, GpuMode::Mangohud]),
+            "--gpu mangohud was given more than once.",
+        );
+    }
+
+    #[test]
+    fn validate_rejects_bumblebee_with_prime() {
+        let error = validate_error(&[GpuMode::Pvkrun, GpuMode::DriPrime]);
+        assert!(
+            error.contains("one is a Bumblebee-era wrapper"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn validate_rejects_two_prime_modes() {
+        assert_eq!(
+            validate_error(&[GpuMode::DriPrime, GpuMode::Switcheroo]),
+            "--gpu dri-prime and --gpu switcheroo both put the command on the GPU; pick one.",
+        );
+    }
+
+    #[test]
+    fn build_args_rejects_nul_bytes() {
+        let error = build_args(&os_strings(&["space-game", "--level=a\0b"])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "argument \"--level=a\\0b\" contains a NUL byte"
+        );
+        assert!(build_args(&os_strings(&["space-game", "--level=ab"])).is_ok());
+    }
+
+    #[test]
+    fn build_env_names_the_variable_with_a_nul_byte() {
+        let env = BTreeMap::from([(OsString::from("LEVEL"), OsString::from("a\0b"))]);
+        let error = build_env(env).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "environment variable LEVEL contains a NUL byte"
+        );
+    }
+
+    #[test]
+    fn select_gpu_rejects_none() {
+        let error = select_gpu(&GpuSelector::Index(1), &[GpuMode::None])
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "--gpu none runs the command as-is, so it can't be combined with picking a GPU.",
+        );
+    }
+
+    #[test]
+    fn select_gpu_rejects_switcheroo() {
+        let error = select_gpu(&GpuSelector::Index(2), &[GpuMode::Switcheroo])
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("switcherooctl list"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn gpu_selector_matches() {
+        let gpu = Gpu {
+            index: 1,
+            pci_address: Some("0000:0a:00.0".to_owned()),
+            vendor: Some(NVIDIA_VENDOR_ID.to_owned()),
+        };
+        assert!(GpuSelector::Index(1).matches(&gpu));
+        assert!(!GpuSelector::Index(0).matches(&gpu));
+        assert!(GpuSelector::Pci("0000:0a:00.0".to_owned()).matches(&gpu));
+        // As `lspci` shows it, without the domain, and in either case.
+        assert!(GpuSelector::Pci("0a:00.0".to_owned()).matches(&gpu));
+        assert!(GpuSelector::Pci("0000:0A:00.0".to_owned()).matches(&gpu));
+        assert!(!GpuSelector::Pci("0001:0a:00.0".to_owned()).matches(&gpu));
+        assert!(!GpuSelector::Pci("0a:00.1".to_owned()).matches(&gpu));
+
+        let gpu = Gpu {
+            pci_address: None,
+            ..gpu
+        };
+        assert!(!GpuSelector::Pci("0a:00.0".to_owned()).matches(&gpu));
+    }
+
+    fn gpu(index: u32, vendor: Option<&str>) -> Gpu {
+        Gpu {
+            index,
+            pci_address: vendor.map(|_| format!("0000:0{}:00.0", index)),
+            vendor: vendor.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn mode_for_gpus() {
+        const INTEL: Option<&str> = Some("0x8086");
+        const AMD: Option<&str> = Some("0x1002");
+        const NVIDIA: Option<&str> = Some(NVIDIA_VENDOR_ID);
+        assert_eq!(mode_for(&[]), GpuMode::None);
+        assert_eq!(mode_for(&[gpu(0, INTEL)]), GpuMode::None);
+        assert_eq!(
+            mode_for(&[gpu(0, INTEL), gpu(1, NVIDIA)]),
+            GpuMode::NvPrimeRun
+        );
+        assert_eq!(mode_for(&[gpu(0, INTEL), gpu(1, AMD)]), GpuMode::DriPrime);
+        // simpledrm took card0, as it does on many laptops.
+        assert_eq!(
+            mode_for(&[gpu(0, None), gpu(1, INTEL), gpu(2, AMD)]),
+            GpuMode::DriPrime
+        );
+        // A single iGPU, and a DisplayLink dock's evdi card: nothing to offload to.
+        assert_eq!(mode_for(&[gpu(0, INTEL), gpu(1, None)]), GpuMode::None);
+        assert_eq!(mode_for(&[gpu(0, None), gpu(1, None)]), GpuMode::None);
+    }
+
+    #[test]
+    fn launch_env_order() {
+        let launch = Launch {
+            modes: vec![GpuMode::Mangohud, GpuMode::DriPrime],
+            wm_class: Some("space-game".to_owned()),
+            vars: vec![
+                vec![var("DRI_PRIME", "0"), var("A", "config")],
+                vec![],
+                vec![var("A", "cli")],
+            ],
+            ..Launch::default()
+        };
+        let (cmd, extra_env) = launch.build(&os_strings(&["space-game"]));
+        assert_eq!(cmd, os_strings(&["mangohud", "space-game"]));
+        // The user's variables come last, so they win.
+        assert_eq!(
+            vars(&extra_env),
+            [
+                var("MANGOHUD", "1"),
+                var("DRI_PRIME", "1"),
+                var("ON_THE_GPU_MODE", "mangohud,dri-prime"),
+                var("SDL_VIDEO_X11_WMCLASS", "space-game"),
+                var("SDL_VIDEO_WAYLAND_WMCLASS", "space-game"),
+                var("DRI_PRIME", "0"),
+                var("A", "config"),
+                var("A", "cli"),
+            ],
+        );
+    }
+
+    #[test]
+    fn launch_with_none_leaves_the_env_alone() {
+        let launch = Launch {
+            modes: vec![GpuMode::None],
+            ..Launch::default()
+        };
+        let (cmd, extra_env) = launch.build(&os_strings(&["space-game"]));
+        assert_eq!(cmd, os_strings(&["space-game"]));
+        assert_eq!(vars(&extra_env), []);
+    }
+
+    #[test]
+    fn launch_binaries() {
+        let launch = Launch {
+            modes: vec![GpuMode::Gamemode, GpuMode::DriPrime, GpuMode::Pvkrun],
+            tracer: Some(Tracer::Strace),
+            sandbox: Some(Sandbox::Firejail),
+            ..Launch::default()
+        };
+        let command = os_strings(&["./space-game", "--fast"]);
+        assert_eq!(
+            launch.binaries(&command),
+            [
+                "firejail",
+                "gamemoderun",
+                "pvkrun",
+                "strace",
+                "./space-game"
+            ],
+        );
+        assert_eq!(
+            launch.binaries(&[]),
+            ["firejail", "gamemoderun", "pvkrun", "strace"],
+        );
+    }
+
+    #[test]
+    fn command_not_found_shows_the_path() {
+        let not_found = |binary: &str, path: Option<&str>| {
+            CommandNotFound {
+                binary: binary.into(),
+                path: path.map(OsString::from),
+            }
+            .to_string()
+        };
+        assert_eq!(
+            not_found("space-game", Some("/usr/bin:/bin")),
+            "'space-game' not found in PATH\nPATH=/usr/bin:/bin",
+        );
+        assert_eq!(
+            not_found("space-game", None),
+            "'space-game' not found in PATH\n(PATH is not set)",
+        );
+        // No search for a path, so no `PATH` either.
+        assert_eq!(
+            not_found("./space-game", Some("/usr/bin")),
+            "'./space-game' not found",
+        );
+    }
+
+    #[test]
+    fn run_options_dir() {
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(RunOptions::default().dir().unwrap(), current_dir);
+        let run = RunOptions {
+            cwd: Some(PathBuf::from("games")),
+            ..RunOptions::default()
+        };
+        assert_eq!(run.dir().unwrap(), current_dir.join("games"));
+        let run = RunOptions {
+            cwd: Some(PathBuf::from("/games")),
+            ..RunOptions::default()
+        };
+        assert_eq!(run.dir().unwrap(), Path::new("/games"));
+    }
+
+    #[test]
+    fn command_file_lines() {
+        assert_eq!(
+            split_command_file(b"space-game\n--level\nthe moon\n"),
+            os_strings(&["space-game", "--level", "the moon"]),
+        );
+        // Without the trailing newline, too.
+        assert_eq!(
+            split_command_file(b"space-game\n--fast"),
+            os_strings(&["space-game", "--fast"]),
+        );
+        // Only one trailing separator is dropped; an empty line before it is an empty argument.
+        assert_eq!(
+            split_command_file(b"space-game\n\n"),
+            os_strings(&["space-game", ""]),
+        );
+        assert_eq!(split_command_file(b""), os_strings(&[]));
+    }
+
+    #[test]
+    fn command_file_crlf() {
+        assert_eq!(
+            split_command_file(b"space-game\r\n--fast\r\n"),
+            os_strings(&["space-game", "--fast"]),
+        );
+    }
+
+    #[test]
+    fn command_file_nuls() {
+        // Newlines are part of the arguments then, and so is a `\r`.
+        assert_eq!(
+            split_command_file(b"space-game\0--level\nthe moon\r\0"),
+            os_strings(&["space-game", "--level\nthe moon\r"]),
+        );
+    }
+
+    #[test]
+    fn command_placeholder() {
+        let command = os_strings(&["gamemoderun", "%command%", "-novid"]);
+        let real_command = os_strings(&["./game", "-windowed"]);
+        assert_eq!(
+            expand_command_placeholder(command, real_command).unwrap(),
+            os_strings(&["gamemoderun", "./game", "-windowed", "-novid"]),
+        );
+        let command = os_strings(&["space-game", "--fast"]);
+        assert_eq!(
+            expand_command_placeholder(command.clone(), vec![]).unwrap(),
+            command,
+        );
+    }
+
+    #[test]
+    fn command_placeholder_with_command_file() {
+        // The file's arguments are appended first, so a `%command%` in there counts, and any
+        // arguments after it go at the very end.
+        let mut command = os_strings(&["gamemoderun"]);
+        command.extend(split_command_file(b"%command%\n-novid\n"));
+        assert_eq!(
+            expand_command_placeholder(command, os_strings(&["./game"])).unwrap(),
+            os_strings(&["gamemoderun", "./game", "-novid"]),
+        );
+        let mut command = os_strings(&["gamemoderun", "%command%"]);
+        command.extend(split_command_file(b"-novid\n"));
+        assert_eq!(
+            expand_command_placeholder(command, os_strings(&["./game"])).unwrap(),
+            os_strings(&["gamemoderun", "./game", "-novid"]),
+        );
+    }
+
+    #[test]
+    fn command_placeholder_errors() {
+        let error = |command: &[&str], real_command: &[&str]| {
+            expand_command_placeholder(os_strings(command), os_strings(real_command))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&["%command%", "%command%"], &["./game"]),
+            "%command% can only be in the command once.",
+        );
+        assert_eq!(
+            error(&["gamemoderun", "%command%"], &[]),
+            "The command has %command% in it, but there's no --command to put there.",
+        );
+        assert_eq!(
+            error(&["space-game"], &["./game"]),
+            "--command only makes sense with %command% in the command.",
+        );
+    }
+
+    #[test]
+    fn bwrap() {
+        let extra_args = os_strings(&["--bind", "/saves", "/saves"]);
+        let cmd = Sandbox::Bwrap.wrap(&extra_args, &os_strings(&["space-game", "--fast"]));
+        let mut expected = os_strings(&[
+            "bwrap",
+            "--ro-bind",
+            "/",
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+        ]);
+        // Whichever of these this system has.
+        for node in gpu_device_nodes() {
+            expected.extend(["--dev-bind".into(), node.clone().into(), node.into()]);
+        }
+        expected.extend(os_strings(&[
+            "--bind",
+            "/saves",
+            "/saves",
+            "--",
+            "space-game",
+            "--fast",
+        ]));
+        assert_eq!(cmd, expected);
+    }
+
+    #[test]
+    fn firejail() {
+        let command = os_strings(&["space-game", "--fast"]);
+        assert_eq!(
+            Sandbox::Firejail.wrap(&[], &command),
+            os_strings(&["firejail", "--", "space-game", "--fast"]),
+        );
+        assert_eq!(
+            Sandbox::Firejail.wrap(&os_strings(&["--net=none"]), &command),
+            os_strings(&["firejail", "--net=none", "--", "space-game", "--fast"]),
+        );
+    }
+
+    #[test]
+    fn tracer_goes_innermost() {
+        let launch = Launch {
+            modes: vec![GpuMode::Gamemode, GpuMode::Pvkrun],
+            tracer: Some(Tracer::Strace),
+            ..Launch::default()
+        };
+        let (cmd, _) = launch.build(&os_strings(&["space-game", "--fast"]));
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "gamemoderun",
+                "pvkrun",
+                "strace",
+                "-f",
+                "space-game",
+                "--fast"
+            ]),
+        );
+    }
+
+    #[test]
+    fn sandbox_goes_outermost() {
+        let launch = Launch {
+            modes: vec![GpuMode::Gamemode, GpuMode::Mangohud],
+            sandbox: Some(Sandbox::Firejail),
+            sandbox_args: os_strings(&["--net=none"]),
+            ..Launch::default()
+        };
+        let (cmd, _) = launch.build(&os_strings(&["space-game"]));
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "firejail",
+                "--net=none",
+                "--",
+                "gamemoderun",
+                "mangohud",
+                "space-game"
+            ]),
+        );
+    }
+
+    #[test]
+    fn firejail_allows_the_tracer() {
+        let launch = Launch {
+            modes: vec![GpuMode::DriPrime],
+            tracer: Some(Tracer::Ltrace),
+            sandbox: Some(Sandbox::Firejail),
+            sandbox_args: os_strings(&["--net=none"]),
+            ..Launch::default()
+        };
+        let (cmd, _) = launch.build(&os_strings(&["space-game"]));
+        assert_eq!(
+            cmd,
+            os_strings(&[
+                "firejail",
+                "--allow-debuggers",
+                "--net=none",
+                "--",
+                "ltrace",
+                "-f",
+                "space-game"
+            ]),
+        );
+    }
+}
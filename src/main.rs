@@ -1,47 +1,597 @@
-use std::ffi::{CString, OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
 
-use clap::Parser;
+use anyhow::{bail, Context};
+use clap::{CommandFactory, Parser};
+use on_the_gpu::{
+    config, detect_gpu_mode, env_file, expand_command_placeholder, find_in_path, merge_env,
+    read_command_file, select_gpu, validate, CommandNotFound, ExtraEnv, GpuMode, GpuSelector,
+    Launch, ModeOptions, NvPrime, Override, Report, RunOptions, Sandbox, Tracer, VkDebug,
+};
+
+mod self_test;
 
 /// Run a program on the (discrete) GPU.
 #[derive(Parser)]
 struct Args {
+    /// How to get the program onto the GPU. May be given more than once, to nest wrappers; the
+    /// first one given is the outermost, e.g. `--gpu gamemode --gpu pvkrun` runs
+    /// `gamemoderun pvkrun <command>`.
+    ///
+    /// If not given, this is taken from the game's section of the config file, if any, or else
+    /// picked from the GPUs in /sys/class/drm: `nv-prime-run` if one of several GPUs is NVIDIA's,
+    /// `dri-prime` for other multi-GPU systems, and `none` otherwise. Unless it's just `none`, the
+    /// command gets the modes in `ON_THE_GPU_MODE`, e.g., `dri-prime,mangohud`.
+    #[arg(long, value_enum)]
+    gpu: Vec<GpuMode>,
+    /// Which GPU to use, as the `N` of `/sys/class/drm/cardN`. For Mesa drivers, this sets
+    /// `DRI_PRIME` to the GPU's PCI address (e.g., `DRI_PRIME=pci-0000_01_00_0`); with `--gpu
+    /// nv-prime-run`, `__NV_PRIME_RENDER_OFFLOAD_PROVIDER` to its `NVIDIA-GN` name. It can't be
+    /// used with `--gpu switcheroo`, which numbers the GPUs its own way; see `--switcheroo-gpu`.
+    #[arg(long, value_name = "N")]
+    gpu_index: Option<u32>,
+    /// Which GPU to use, by its PCI address, as `lspci` shows it: e.g., `01:00.0`, or
+    /// `0000:01:00.0`. Otherwise the same as `--gpu-index`.
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "gpu_index")]
+    gpu_pci: Option<String>,
+    /// With `--gpu switcheroo`, which GPU to use, as its index in `switcherooctl list`, where the
+    /// default GPU comes first; passed as-is to `switcherooctl launch --gpu`. Defaults to 1, the
+    /// first GPU that isn't the default.
+    #[arg(long, value_name = "N", conflicts_with_all = ["gpu_index", "gpu_pci"])]
+    switcheroo_gpu: Option<u32>,
+    /// With `--gpu nv-prime-run`, which NVIDIA GPU to render on, as an
+    /// `__NV_PRIME_RENDER_OFFLOAD_PROVIDER` name: `NVIDIA-G0` for the first NVIDIA GPU,
+    /// `NVIDIA-G1` for the second, and so on (under X11, `xrandr --listproviders` lists them). By
+    /// default it's not set, and the driver picks; that's fine with only one NVIDIA GPU.
+    #[arg(
+        long,
+        value_name = "PROVIDER",
+        conflicts_with_all = ["gpu_index", "gpu_pci"]
+    )]
+    nv_provider: Option<String>,
+    /// With `--gpu nv-prime-run`, don't set `__GLX_VENDOR_LIBRARY_NAME=nvidia`, which only GLX
+    /// (X11 OpenGL) looks at. Only leave it out for native Wayland programs: X11 programs running
+    /// under XWayland, as most games do even in a Wayland session, need it to use the NVIDIA GPU
+    /// for OpenGL.
+    #[arg(long)]
+    no_nv_glx: bool,
+    /// With `--gpu nv-prime-run`, don't set `__VK_LAYER_NV_optimus=NVIDIA_only`, which makes
+    /// Vulkan programs see only the NVIDIA GPU. Leave it out for programs that pick a GPU
+    /// themselves, or use several at once; they can then see the integrated GPU too.
+    #[arg(long)]
+    no_vk_layer_override: bool,
+    /// Arguments for `gamescope`, with `--gpu gamescope`, separated by whitespace. E.g.,
+    /// `--gamescope-args '-W 2560 -H 1440 -f'`. These go after any from the config file's
+    /// `[modes.gamescope]`, so they win.
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    gamescope_args: Option<OsString>,
+    /// Make the Vulkan loader (and, optionally, the drivers) log what they're doing. This is most
+    /// useful when debugging why a game doesn't see the GPU.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "loader"
+    )]
+    vk_debug: Option<VkDebug>,
+    /// Run the command under a tracer, which writes its trace to stderr. It goes innermost, right
+    /// around the command, so the GPU mode's wrappers aren't traced.
+    #[arg(long, value_enum, value_name = "TRACER")]
+    trace: Option<Tracer>,
+    /// Run the command in a sandbox. It goes outermost, so the GPU mode's wrappers run inside the
+    /// sandbox: the sandbox would otherwise drop the `LD_PRELOAD` that `gamemoderun` and
+    /// `mangohud` set. With `--trace`, `firejail` is given `--allow-debuggers`, which its seccomp
+    /// filter needs to let the tracer work.
+    #[arg(long, value_enum, value_name = "SANDBOX")]
+    sandbox: Option<Sandbox>,
+    /// More arguments for the sandbox, with `--sandbox`, separated by whitespace. E.g.,
+    /// `--sandbox-args '--net=none'`, or `--sandbox-args '--bind /path/to/saves /path/to/saves'`
+    /// to let the game write to a directory with `bwrap`.
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    sandbox_args: Option<OsString>,
+    /// Ask SDL games to use this window class (X11) / app ID (Wayland), which window managers and
+    /// taskbars group windows by.
+    #[arg(long, value_name = "NAME")]
+    wm_class: Option<String>,
+    /// Set an environment variable for the command, as `KEY=VALUE`. May be given more than once.
+    /// These win over variables set by the GPU modes or the config file.
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+    /// Set the environment variables in this dotenv-style file for the command. May be given more
+    /// than once. These win over variables from the config file, but `--env` wins over these.
+    ///
+    /// Each line is `KEY=VALUE`, optionally prefixed with `export `; blank lines and lines
+    /// starting with `#` are ignored. An unquoted value ends at ` #`, which starts a comment. In
+    /// a `'…'` value everything is literal; in a `"…"` one, `\"`, `\\` and `\n` (a newline) are
+    /// escapes, and other backslashes are kept as-is. A quoted value may be followed by a comment.
+    #[arg(long, value_name = "PATH")]
+    env_file: Vec<PathBuf>,
+    /// Run the command with only the variables set by on-the-gpu, instead of on top of our own
+    /// environment. Keep at least `DISPLAY` / `WAYLAND_DISPLAY` (and usually `HOME`, `PATH` and
+    /// `XDG_RUNTIME_DIR`) with `--keep-env`, or graphical programs won't start.
+    #[arg(long)]
+    clear_env: bool,
+    /// With `--clear-env`, pass this variable on to the command, if it's set. May be given more
+    /// than once.
+    #[arg(long, value_name = "KEY")]
+    keep_env: Vec<String>,
+    /// Print the variables the GPU mode would set, as `export` lines for a shell to `eval`, and
+    /// exit without running anything. No command is needed with this.
+    #[arg(long)]
+    print_env: bool,
+    /// Print the command being run, with its CWD and the variables that decide which libraries it
+    /// loads (`PATH`, `LD_LIBRARY_PATH`, `VK_DRIVER_FILES`, …), to stderr. Give it twice (`-vv`)
+    /// to also print the environment variables being added.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Print the command that would be run, and exit without running it.
+    #[arg(long)]
+    dry_run: bool,
+    /// Check that the wrappers the GPU modes need, and the command itself, are in PATH, and exit
+    /// without running anything. Exits with status 1 if any are missing.
+    #[arg(long)]
+    check: bool,
+    /// Check that the GPU modes work: run `glxinfo -B` (or, failing that, `vulkaninfo --summary`)
+    /// with and without them, and compare the GPUs they render on. No game name or command is
+    /// needed with this. Exits with status 1 if both render on the same GPU, unless that's
+    /// expected: none of the modes offloads (e.g., just `--gpu gamemode`), and no GPU is picked.
+    #[arg(long)]
+    self_test: bool,
     // TODO: this is going to be used for logging, at some point.
-    /// A name for the game, this is used to build the filename for the log file.
-    game_name: String,
+    /// A name for the game, this is used to find its settings in the config file, and to build
+    /// the filename for the log file.
+    #[arg(required_unless_present_any = ["generate_completions", "self_test"])]
+    game_name: Option<String>,
+    /// Print a completion script for the given shell, and exit.
+    #[arg(long, value_name = "SHELL", hide = true)]
+    generate_completions: Option<clap_complete::Shell>,
+    /// Run the command in this directory (with `PWD` set to it), e.g., a game's install directory;
+    /// some games need that.
+    #[arg(long, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+    /// A shell command to run before the command, e.g., to mount a ramdisk. May be given more than
+    /// once; they're run in order. If one fails, the command isn't run.
+    #[arg(long, value_name = "CMD")]
+    pre_cmd: Vec<OsString>,
+    /// Run the command even if a `--pre-cmd` fails.
+    #[arg(long)]
+    ignore_pre_cmd_failure: bool,
+    /// Run the command (and any `--pre-cmd`s) with stdin connected to /dev/null, so it can't block
+    /// reading from it. By default stdin is inherited, so prompts still work.
+    #[arg(long)]
+    no_stdin: bool,
+    /// Run the command at this niceness, from -20 (highest priority) to 19 (lowest). Going below
+    /// 0 needs privileges (`CAP_SYS_NICE`, or an `RLIMIT_NICE`); if we lack them, we warn and run
+    /// the command anyway.
+    #[arg(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19)
+    )]
+    nice: Option<i32>,
+    /// Run the command with the round-robin real-time scheduling policy (`SCHED_RR`), at the
+    /// lowest real-time priority. This needs `CAP_SYS_NICE` (or an `RLIMIT_RTPRIO`); if we lack
+    /// it, we warn and run the command anyway.
+    #[arg(long)]
+    sched_rr: bool,
+    /// Read more arguments for the command from this file (or stdin, for `-`), and append them to
+    /// the ones given on the command line. Arguments are one per line (Windows line endings are
+    /// fine), or, if the file contains any NUL bytes, separated by those, as with `xargs -0`.
+    #[arg(long, value_name = "PATH")]
+    command_file: Option<PathBuf>,
+    /// An argument of the command that `%command%` in the command stands for, as in Steam's
+    /// launch options; given once for each argument. See the command, below.
+    #[arg(
+        long = "command",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        requires = "command"
+    )]
+    real_command: Vec<OsString>,
     /// The arguments of the command to run, including the binary. E.g., `space-game --aliens`.
+    /// Everything from the binary on is passed to the command as-is, flags included; no `--`
+    /// is needed.
+    ///
+    /// As in Steam's launch options, a `%command%` in here is replaced by the `--command`
+    /// arguments, so a launch options string can be reused as-is: e.g.,
+    /// `on-the-gpu --command ./game --command -windowed space-game gamemoderun %command% -novid`
+    /// runs `gamemoderun ./game -windowed -novid`. Only the token itself is recognized;
+    /// `VAR=value` assignments before it are not (use `--env`). `--command-file` arguments still
+    /// go at the very end.
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     command: Vec<OsString>,
 }
 
-fn main() {
-    let args = Args::parse();
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    if let Some(shell) = args.generate_completions {
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Args::command(), "on-the-gpu", &mut script);
+        return write_stdout(&script, "the completion script");
+    }
+    let mut config = config::load()?;
+    let game_config = match &args.game_name {
+        Some(game_name) => config.games.remove(game_name).unwrap_or_default(),
+        // Only with `--self-test`.
+        None => config::GameConfig::default(),
+    };
+    if args.gpu.is_empty() {
+        args.gpu = game_config.gpu;
+    }
+    if args.gpu.is_empty() {
+        let mode = detect_gpu_mode().unwrap_or_else(|err| {
+            eprintln!("Failed to list GPUs, assuming there's only one: {}", err);
+            GpuMode::None
+        });
+        eprintln!("No --gpu given; detected --gpu {}.", mode);
+        args.gpu.push(mode);
+    }
+    validate(&args.gpu)?;
+    if let Some(path) = &args.command_file {
+        let more_args = read_command_file(path)?;
+        args.command.extend(more_args);
+    }
+    args.command = expand_command_placeholder(
+        std::mem::take(&mut args.command),
+        std::mem::take(&mut args.real_command),
+    )?;
+    let probe = if args.self_test {
+        if !args.command.is_empty() {
+            bail!("--self-test runs its own command, so don't give one.");
+        }
+        let probe = self_test::find_probe()?;
+        args.command = probe.args();
+        Some(probe)
+    } else {
+        None
+    };
+    let selector = match (args.gpu_index, args.gpu_pci.take()) {
+        (Some(index), _) => Some(GpuSelector::Index(index)),
+        (None, Some(address)) => Some(GpuSelector::Pci(address)),
+        (None, None) => None,
+    };
+    let chosen_gpu = selector
+        .map(|selector| select_gpu(&selector, &args.gpu))
+        .transpose()?;
+    if args.switcheroo_gpu.is_some() && !args.gpu.contains(&GpuMode::Switcheroo) {
+        bail!("--switcheroo-gpu only makes sense with --gpu switcheroo.");
+    }
+    if args.nv_provider.is_some() && !args.gpu.contains(&GpuMode::NvPrimeRun) {
+        bail!("--nv-provider only makes sense with --gpu nv-prime-run.");
+    }
+    if args.no_nv_glx && !args.gpu.contains(&GpuMode::NvPrimeRun) {
+        bail!("--no-nv-glx only makes sense with --gpu nv-prime-run.");
+    }
+    if args.no_vk_layer_override && !args.gpu.contains(&GpuMode::NvPrimeRun) {
+        bail!("--no-vk-layer-override only makes sense with --gpu nv-prime-run.");
+    }
+    if args.gamescope_args.is_some() && !args.gpu.contains(&GpuMode::Gamescope) {
+        bail!("--gamescope-args only makes sense with --gpu gamescope.");
+    }
+    if args.sandbox_args.is_some() && args.sandbox.is_none() {
+        bail!("--sandbox-args only makes sense with --sandbox.");
+    }
+    if !args.keep_env.is_empty() && !args.clear_env {
+        bail!("--keep-env only makes sense with --clear-env.");
+    }
+    let mut options = ModeOptions {
+        gpu: chosen_gpu,
+        nv_prime: NvPrime {
+            provider: args.nv_provider.take(),
+            glx: !args.no_nv_glx,
+            vk_layer_override: !args.no_vk_layer_override,
+        },
+        switcheroo_gpu: args.switcheroo_gpu,
+        ..ModeOptions::default()
+    };
+    options.gamescope_args = config
+        .modes
+        .gamescope
+        .args
+        .into_iter()
+        .map(OsString::from)
+        .chain(
+            args.gamescope_args
+                .as_deref()
+                .map(split_whitespace)
+                .into_iter()
+                .flatten(),
+        )
+        .collect();
+    let mut launch = Launch {
+        modes: args.gpu.clone(),
+        options,
+        tracer: args.trace,
+        sandbox: args.sandbox,
+        sandbox_args: args
+            .sandbox_args
+            .as_deref()
+            .map(split_whitespace)
+            .unwrap_or_default(),
+        vk_debug: args.vk_debug,
+        wm_class: args.wm_class.take(),
+        ..Launch::default()
+    };
+    if let Some(probe) = probe {
+        probe.adjust(&mut launch);
+    }
+    if args.check {
+        let mut all_found = true;
+        for binary in launch.binaries(&args.command) {
+            // A relative path is run from `--cwd`, which isn't applied yet.
+            let found = match &args.cwd {
+                Some(dir) if binary.as_bytes().contains(&b'/') => find_in_path(dir.join(binary)),
+                _ => find_in_path(binary),
+            };
+            match found {
+                Some(path) => println!("{}: {}", binary.to_string_lossy(), path.display()),
+                None => {
+                    println!("{}: missing", binary.to_string_lossy());
+                    all_found = false;
+                }
+            }
+        }
+        if args.command.is_empty() {
+            println!("No command given to check.");
+        }
+        std::process::exit(if all_found { 0 } else { 1 });
+    }
+    // Later sets win over earlier ones, so these go from least to most specific.
+    let config_vars = game_config.env.into_iter();
+    launch.vars.push(
+        config_vars
+            .map(|(key, value)| (key, value.into()))
+            .collect(),
+    );
+    for path in &args.env_file {
+        launch.vars.push(env_file::read(path)?);
+    }
+    let cli_vars = args.env.into_iter();
+    launch
+        .vars
+        .push(cli_vars.map(|(key, value)| (key, value.into())).collect());
+    let (cmd_to_run, extra_env) = launch.build(&args.command);
+
+    if args.print_env {
+        // Only the value that wins for each variable, as when running the command.
+        let mut exports = Vec::new();
+        let (env, overrides) = merge_env(BTreeMap::new(), &extra_env);
+        warn_overrides(&overrides);
+        for (key, value) in env {
+            let Some(key) = key.to_str().filter(|key| env_file::is_valid_key(key)) else {
+                bail!(
+                    "{:?} isn't a valid variable name, so it can't be exported.",
+                    key
+                );
+            };
+            exports.extend_from_slice(format!("export {}=", key).as_bytes());
+            exports.extend_from_slice(shell_quote(&value).as_bytes());
+            exports.push(b'\n');
+        }
+        return write_stdout(&exports, "the variables");
+    }
+
     if args.command.is_empty() {
-        eprintln!("Need at least 1 argument for the command to run.");
-        std::process::exit(1);
+        bail!("Need at least 1 argument for the command to run.");
+    }
+    // Most likely a misspelling of one of our options; clap takes anything it doesn't know as the
+    // start of the command. It could be a real binary, though, so don't refuse to run it.
+    if args.command[0].as_bytes().starts_with(b"-") {
+        eprintln!(
+            "on-the-gpu: warning: the command to run, {:?}, looks like an option rather than a \
+             binary; is it a misspelled on-the-gpu option (see --help)?",
+            args.command[0]
+        );
+    }
+    let run = RunOptions {
+        cwd: args.cwd,
+        clear_env: args.clear_env,
+        keep_env: args.keep_env,
+        no_stdin: args.no_stdin,
+        pre_cmds: args.pre_cmd,
+        ignore_pre_cmd_failure: args.ignore_pre_cmd_failure,
+        nice: args.nice,
+        sched_rr: args.sched_rr,
+    };
+    if args.dry_run {
+        let (env, overrides) = run.env(&extra_env)?;
+        warn_overrides(&overrides);
+        print_cmd(
+            &cmd_to_run,
+            run.dir(),
+            &extra_env,
+            &env,
+            args.verbose.max(2),
+        );
+        return Ok(());
+    }
+    if let Some(probe) = probe {
+        // Only now that we're running something; this doesn't matter to --dry-run.
+        launch.check_installed()?;
+        let base_env = run.base_env()?;
+        let (env, overrides) = merge_env(base_env.clone(), &extra_env);
+        warn_overrides(&overrides);
+        print_cmd(&cmd_to_run, run.dir(), &extra_env, &env, args.verbose);
+        let works = self_test::run(probe, &launch, &cmd_to_run, &base_env, &env)?;
+        std::process::exit(if works { 0 } else { 1 });
+    }
+    let verbose = args.verbose;
+    let Err(err) = launch.exec(&args.command, &run, |report| match report {
+        Report::Overrides(overrides) => warn_overrides(overrides),
+        Report::PreCmdFailed { pre_cmd, status } => eprintln!(
+            "on-the-gpu: --pre-cmd {:?} failed ({}); carrying on.",
+            pre_cmd, status
+        ),
+        Report::NiceFailed { nice, err } => {
+            eprintln!("on-the-gpu: failed to set niceness to {}: {}", nice, err)
+        }
+        Report::SchedRrFailed(err) => eprintln!("on-the-gpu: failed to set SCHED_RR: {}", err),
+        Report::Exec {
+            command,
+            extra_env,
+            env,
+        } => print_cmd(command, std::env::current_dir(), extra_env, env, verbose),
+    });
+    if let Some(not_found) = err.downcast_ref::<CommandNotFound>() {
+        // Like a shell would.
+        eprintln!("on-the-gpu: {}", not_found);
+        std::process::exit(127);
+    }
+    Err(err)
+}
+
+/// Write `output` (`what`, for the error) to stdout. A reader that stops early, e.g., `head`, isn't
+/// an error.
+fn write_stdout(output: &[u8], what: &str) -> anyhow::Result<()> {
+    match std::io::stdout().write_all(output) {
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.with_context(|| format!("failed to write {}", what)),
+    }
+}
+
+/// Split `s` on ASCII whitespace, leaving out empty parts.
+fn split_whitespace(s: &OsStr) -> Vec<OsString> {
+    s.as_bytes()
+        .split(u8::is_ascii_whitespace)
+        .filter(|part| !part.is_empty())
+        .map(|part| OsStr::from_bytes(part).to_owned())
+        .collect()
+}
+
+/// The variables that decide which libraries, and so which GPU drivers, the command loads.
+const LIBRARY_PATH_VARS: [&str; 5] = [
+    "PATH",
+    "LD_LIBRARY_PATH",
+    "VK_ICD_FILENAMES",
+    "VK_DRIVER_FILES",
+    "__EGL_VENDOR_LIBRARY_FILENAMES",
+];
+
+/// Print the command that is about to be run, in `cwd` (to stderr), one argument per line, if
+/// `verbosity` is at least 1. From 2, also print the environment variables we're adding.
+fn print_cmd(
+    cmd: &[OsString],
+    cwd: std::io::Result<PathBuf>,
+    extra_env: &[ExtraEnv],
+    env: &BTreeMap<OsString, OsString>,
+    verbosity: u8,
+) {
+    if verbosity == 0 {
+        return;
+    }
+    eprintln!("== Start ==");
+    eprintln!("CWD: {:?}", cwd);
+    eprintln!("Command:");
+    for (idx, arg) in cmd.iter().enumerate() {
+        eprintln!("  argv[{}] = {:?}", idx, arg);
     }
-    println!("== Start ==");
-    println!("CWD: {:?}", std::env::current_dir());
-    println!("Arguments:");
-    for (idx, arg) in args.command.iter().enumerate() {
-        println!("  argv[{}] = {:?}", idx, arg);
+    // Whatever the launcher left in these is a common reason for the wrong driver being loaded.
+    eprintln!("Library paths:");
+    for key in LIBRARY_PATH_VARS {
+        match env.get(OsStr::new(key)) {
+            Some(value) => eprintln!("  {}={}", key, value.to_string_lossy()),
+            None => eprintln!("  {} is unset", key),
+        }
     }
+    if verbosity < 2 {
+        return;
+    }
+    // Only the value that wins for each variable, as it's passed to the command.
+    let (added, _) = merge_env(BTreeMap::new(), extra_env);
+    if added.is_empty() {
+        eprintln!("Environment: (same)");
+    } else {
+        eprintln!("Environment:");
+        for key in added.keys() {
+            eprintln!("  {}={}", key.to_string_lossy(), env[key].to_string_lossy());
+        }
+    }
+}
 
-    let mut to_exec_args = Vec::<CString>::new();
-    to_exec_args.push(string_to_cstring("pvkrun".to_owned()));
-    for arg in args.command.iter() {
-        to_exec_args.push(os_str_to_cstring(arg));
+/// Warn about the variables that one of the sets of variables overrode with a different value
+/// (see [`merge_env`]); that might not be what the user expected.
+fn warn_overrides(overrides: &[Override]) {
+    for Override {
+        key,
+        old_value,
+        new_value,
+    } in overrides
+    {
+        eprintln!(
+            "on-the-gpu: overriding {}={:?} with {:?}",
+            key, old_value, new_value,
+        );
     }
-    nix::unistd::execvp(&to_exec_args[0], &to_exec_args).unwrap();
 }
 
-fn os_str_to_cstring(s: &OsStr) -> CString {
-    let mut bytes = s.as_bytes().to_owned();
-    bytes.push(0);
-    CString::from_vec_with_nul(bytes).unwrap()
+/// Quote `s` for a POSIX shell, if it needs it.
+fn shell_quote(s: &OsStr) -> OsString {
+    let is_safe = |byte: &u8| byte.is_ascii_alphanumeric() || b"_-+=.,:/@%".contains(byte);
+    if !s.is_empty() && s.as_bytes().iter().all(is_safe) {
+        return s.to_owned();
+    }
+    let mut quoted = vec![b'\''];
+    for &byte in s.as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
+
+/// Parses a `KEY=VALUE` argument to `--env`. The value may contain more `=`s, as in
+/// `WINEDLLOVERRIDES=dxgi=n,b`.
+fn parse_env_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        None => Err("expected KEY=VALUE".to_owned()),
+        Some(("", _)) => Err("the variable name is empty".to_owned()),
+        Some((key, _)) if !env_file::is_valid_key(key) => Err(format!(
+            "{:?} isn't a valid variable name (letters, digits and underscores, not starting \
+             with a digit)",
+            key
+        )),
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+    }
 }
 
-fn string_to_cstring(s: String) -> CString {
-    let mut bytes = s.into_bytes();
-    bytes.push(0);
-    CString::from_vec_with_nul(bytes).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_after_the_command_are_part_of_it() {
+        let args = Args::try_parse_from(["on-the-gpu", "g", "space-game", "--aliens"]).unwrap();
+        assert_eq!(args.game_name.as_deref(), Some("g"));
+        assert_eq!(args.command, ["space-game", "--aliens"]);
+    }
+
+    #[test]
+    fn shell_quote_leaves_safe_values_alone() {
+        assert_eq!(
+            shell_quote(OsStr::new("pci-0000_01_00.0")),
+            "pci-0000_01_00.0"
+        );
+        assert_eq!(shell_quote(OsStr::new("dxgi=n,b")), "dxgi=n,b");
+        assert_eq!(shell_quote(OsStr::new("/usr/lib:/lib")), "/usr/lib:/lib");
+    }
+
+    #[test]
+    fn shell_quote_quotes_the_rest() {
+        assert_eq!(shell_quote(OsStr::new("")), "''");
+        assert_eq!(shell_quote(OsStr::new("it's")), "'it'\\''s'");
+        assert_eq!(shell_quote(OsStr::new("a b $HOME")), "'a b $HOME'");
+        assert_eq!(
+            shell_quote(OsStr::from_bytes(b"caf\xe9")),
+            OsStr::from_bytes(b"'caf\xe9'"),
+        );
+    }
 }
@@ -0,0 +1,196 @@
+//! `--self-test`: check that the GPU modes get a program onto another GPU.
+//!
+//! This runs a probe that prints which GPU it renders on, once as-is and once under the GPU modes,
+//! and compares the two.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+
+use on_the_gpu::{find_in_path, Launch};
+
+/// How long a probe gets to report its renderer, before it's killed. Starting a GPU driver can
+/// take a while, but not this long.
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A program that reports which GPU it renders on.
+pub struct Probe {
+    /// The command to run.
+    pub command: &'static [&'static str],
+    /// What the line with the renderer's name starts with, in the probe's output.
+    renderer_prefix: &'static str,
+    /// Whether it renders with GLX, i.e., X11 OpenGL.
+    uses_glx: bool,
+}
+
+impl Probe {
+    /// The command to run, for [`Launch::build`].
+    pub fn args(&self) -> Vec<OsString> {
+        self.command.iter().map(OsString::from).collect()
+    }
+
+    /// Set up `launch` for running this probe: a GLX one needs nv-prime-run's
+    /// `__GLX_VENDOR_LIBRARY_NAME`, even with `--no-nv-glx`, or it stays on the default GPU
+    /// whether offload works or not.
+    pub fn adjust(&self, launch: &mut Launch) {
+        if self.uses_glx {
+            launch.options.nv_prime.glx = true;
+        }
+    }
+}
+
+/// The probes we can use, in order of preference.
+const PROBES: [Probe; 2] = [
+    Probe {
+        command: &["glxinfo", "-B"],
+        renderer_prefix: "OpenGL renderer string:",
+        uses_glx: true,
+    },
+    // Here the first device listed is the one a Vulkan program would pick; `DRI_PRIME` reorders
+    // them.
+    Probe {
+        command: &["vulkaninfo", "--summary"],
+        renderer_prefix: "deviceName",
+        uses_glx: false,
+    },
+];
+
+/// Pick the first probe that's installed.
+pub fn find_probe() -> anyhow::Result<&'static Probe> {
+    PROBES
+        .iter()
+        .find(|probe| find_in_path(probe.command[0]).is_some())
+        .context(
+            "--self-test needs `glxinfo` (usually packaged in mesa-utils) or `vulkaninfo` \
+             (vulkan-tools), and neither is in PATH.",
+        )
+}
+
+/// Run `probe` as-is, with `base_env`, and as `cmd_to_run` (the probe as `launch` runs it), with
+/// `env`, and report whether the latter renders on another GPU. Returns whether it does, or
+/// whether it shouldn't: no mode offloads, e.g., with `--gpu none` or just `--gpu gamemode`, and
+/// no GPU was picked.
+pub fn run(
+    probe: &Probe,
+    launch: &Launch,
+    cmd_to_run: &[OsString],
+    base_env: &BTreeMap<OsString, OsString>,
+    env: &BTreeMap<OsString, OsString>,
+) -> anyhow::Result<bool> {
+    let offloads = launch.modes.iter().any(|mode| mode.offload().is_some());
+    let moves = offloads || launch.options.gpu.is_some();
+    let modes = launch
+        .modes
+        .iter()
+        .map(|mode| format!("--gpu {}", mode))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let default_renderer = renderer(probe, &probe.args(), base_env)?;
+    println!("Without on-the-gpu: {}", default_renderer);
+    let renderer = renderer(probe, cmd_to_run, env)?;
+    println!("With {}: {}", modes, renderer);
+    if !moves {
+        println!(
+            "That's expected: {} doesn't move the program to another GPU.",
+            modes
+        );
+        Ok(true)
+    } else if renderer != default_renderer {
+        println!("Offload works.");
+        Ok(true)
+    } else {
+        println!("Both render on the same GPU, so offload isn't working.");
+        Ok(false)
+    }
+}
+
+/// Run `cmd` (`probe`, maybe wrapped), and find the renderer in its output.
+fn renderer(
+    probe: &Probe,
+    cmd: &[OsString],
+    env: &BTreeMap<OsString, OsString>,
+) -> anyhow::Result<String> {
+    let binary = cmd[0].to_string_lossy();
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .env_clear()
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run `{}`", binary))?;
+    // The probes print a few KiB at most, so they won't block on a full pipe before exiting.
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "`{}` didn't finish within {} seconds, so it was killed.",
+                binary,
+                TIMEOUT.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let mut output = Vec::new();
+    child
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .read_to_end(&mut output)
+        .with_context(|| format!("failed to read the output of `{}`", binary))?;
+    let output = String::from_utf8_lossy(&output);
+    let renderer = output.lines().find_map(|line| {
+        let renderer = line.trim_start().strip_prefix(probe.renderer_prefix)?;
+        Some(renderer.trim_start_matches([' ', '\t', '=']).trim_end())
+    });
+    match renderer {
+        Some(renderer) => Ok(renderer.to_owned()),
+        None => bail!("`{}` ({}) didn't report a renderer.", binary, status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use on_the_gpu::GpuMode;
+
+    /// The `__GLX_VENDOR_LIBRARY_NAME` that `probe` is run with, under `--gpu nv-prime-run
+    /// --no-nv-glx`.
+    fn glx_vendor(probe: &Probe) -> Option<OsString> {
+        let mut launch = Launch {
+            modes: vec![GpuMode::NvPrimeRun],
+            ..Launch::default()
+        };
+        launch.options.nv_prime.glx = false;
+        probe.adjust(&mut launch);
+        let (cmd, extra_env) = launch.build(&probe.args());
+        assert_eq!(cmd, probe.args());
+        extra_env
+            .iter()
+            .flatten()
+            .find(|(key, _)| key == "__GLX_VENDOR_LIBRARY_NAME")
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn glxinfo_gets_the_glx_vendor() {
+        assert_eq!(PROBES[0].args(), ["glxinfo", "-B"]);
+        assert_eq!(glx_vendor(&PROBES[0]), Some("nvidia".into()));
+    }
+
+    #[test]
+    fn vulkaninfo_goes_by_the_options() {
+        assert_eq!(PROBES[1].args(), ["vulkaninfo", "--summary"]);
+        assert_eq!(glx_vendor(&PROBES[1]), None);
+    }
+}
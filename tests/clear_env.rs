@@ -0,0 +1,20 @@
+//! `--clear-env`: the command gets only the variables kept with `--keep-env`.
+
+use std::process::Command;
+
+#[test]
+fn clear_env_keeps_only_the_kept_variables() {
+    let output = Command::new(env!("CARGO_BIN_EXE_on-the-gpu"))
+        .args(["--gpu", "none", "--clear-env"])
+        .args(["--keep-env", "KEPT", "--keep-env", "KEPT_BUT_UNSET"])
+        .args(["g", "--", "printenv"])
+        // Not the user's config file.
+        .env("XDG_CONFIG_HOME", "/nonexistent")
+        .env("KEPT", "1")
+        .env("DROPPED", "2")
+        .env_remove("KEPT_BUT_UNSET")
+        .output()
+        .expect("failed to run on-the-gpu");
+    assert!(output.status.success(), "on-the-gpu failed: {:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "KEPT=1\n");
+}
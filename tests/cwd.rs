@@ -0,0 +1,22 @@
+//! `--cwd`: the command runs in the given directory, and `PWD` says so.
+
+use std::process::Command;
+
+#[test]
+fn cwd_sets_pwd() {
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_on-the-gpu"))
+        .args(["--gpu", "none", "--cwd"])
+        .arg(&dir)
+        .args(["g", "--", "printenv", "PWD"])
+        // Not the user's config file.
+        .env("XDG_CONFIG_HOME", "/nonexistent")
+        .env("PWD", "/somewhere/else")
+        .output()
+        .expect("failed to run on-the-gpu");
+    assert!(output.status.success(), "on-the-gpu failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n", dir.display())
+    );
+}
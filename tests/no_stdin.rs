@@ -0,0 +1,35 @@
+//! `--no-stdin`: the command reads `/dev/null` instead of our stdin.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run `on-the-gpu` with `args`, feeding it "hello" on stdin, and return what the command wrote.
+fn run_with_stdin(args: &[&str]) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_on-the-gpu"))
+        .args(args)
+        // Not the user's config file.
+        .env("XDG_CONFIG_HOME", "/nonexistent")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run on-the-gpu");
+    let mut stdin = child.stdin.take().unwrap();
+    // The command may not read it all, so a broken pipe is fine.
+    let _ = stdin.write_all(b"hello");
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "on-the-gpu failed: {:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn stdin_is_passed_on() {
+    let output = run_with_stdin(&["--gpu", "none", "g", "--", "cat"]);
+    assert_eq!(output, "hello");
+}
+
+#[test]
+fn no_stdin_gives_dev_null() {
+    let output = run_with_stdin(&["--gpu", "none", "--no-stdin", "g", "--", "cat"]);
+    assert_eq!(output, "");
+}
@@ -0,0 +1,68 @@
+//! `--pre-cmd`: a failing one stops the command, unless `--ignore-pre-cmd-failure` is given.
+
+use std::process::{Command, Output};
+
+/// Run `on-the-gpu` with `args`.
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_on-the-gpu"))
+        .args(args)
+        // Not the user's config file.
+        .env("XDG_CONFIG_HOME", "/nonexistent")
+        .output()
+        .expect("failed to run on-the-gpu")
+}
+
+#[test]
+fn failing_pre_cmd_stops_the_command() {
+    let output = run(&[
+        "--gpu",
+        "none",
+        "--pre-cmd",
+        "false",
+        "g",
+        "--",
+        "echo",
+        "ran",
+    ]);
+    assert!(
+        !output.status.success(),
+        "on-the-gpu succeeded: {:?}",
+        output
+    );
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn ignore_pre_cmd_failure_runs_the_command() {
+    let output = run(&[
+        "--gpu",
+        "none",
+        "--pre-cmd",
+        "false",
+        "--ignore-pre-cmd-failure",
+        "g",
+        "--",
+        "echo",
+        "ran",
+    ]);
+    assert!(output.status.success(), "on-the-gpu failed: {:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ran\n");
+}
+
+#[test]
+fn pre_cmds_run_in_order_before_the_command() {
+    let output = run(&[
+        "--gpu",
+        "none",
+        "--pre-cmd",
+        "echo one",
+        "--pre-cmd",
+        "echo two",
+        "g",
+        "--",
+        "echo",
+        "ran",
+    ]);
+    assert!(output.status.success(), "on-the-gpu failed: {:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "one\ntwo\nran\n");
+}